@@ -0,0 +1,354 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::model::{DataBaseErrorType, DataBaseItem, DataType, Store, User, ViewStats};
+
+/// version of the archive layout, kept in `VERSION_ENTRY`
+const FORMAT: u32 = 1;
+const VERSION_ENTRY: &str = "version";
+/// one json `User` per line
+const USERS_ENTRY: &str = "users.ndjson";
+/// one json `Paste` per line
+const PASTES_ENTRY: &str = "pastes.ndjson";
+/// contents are kept under their content key, once for every paste sharing
+/// them
+const CONTENTS_DIR: &str = "contents/";
+
+/// a paste as listed in an archive, whatever backend it was kept in
+#[derive(Serialize, Deserialize)]
+struct Paste {
+    uuid: Uuid,
+    short: String,
+    custom_url: Option<String>,
+    hash: String,
+    secret_hash: String,
+    owner: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    destroy_time: Option<DateTime<Utc>>,
+    max_views: Option<u64>,
+    views: u64,
+    last_viewed_at: Option<DateTime<Utc>>,
+    kind: Kind,
+    size: u64,
+    /// name of the content under `CONTENTS_DIR`
+    content: String,
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Kind {
+    Text,
+    Link,
+    Binary,
+}
+
+impl From<&DataBaseItem> for Paste {
+    fn from(item: &DataBaseItem) -> Self {
+        let kind = match item.data {
+            DataType::Text(_) | DataType::Blob { text: true, .. } => Kind::Text,
+            DataType::ShortLink(_) => Kind::Link,
+            DataType::Binary(_) | DataType::Blob { text: false, .. } => Kind::Binary,
+        };
+        Paste {
+            uuid: item.uuid,
+            short: item.short.clone(),
+            custom_url: item.custom_url.clone(),
+            hash: item.hash.clone(),
+            secret_hash: item.secret_hash.clone(),
+            owner: item.owner.clone(),
+            created_at: item.created_at,
+            updated_at: item.updated_at,
+            destroy_time: item.destroy_time,
+            max_views: item.max_views,
+            views: item.stats.views,
+            last_viewed_at: item.stats.last_viewed_at,
+            kind,
+            size: item.data.size(),
+            content: item.content_key(),
+        }
+    }
+}
+
+impl Paste {
+    fn into_item(self, data: DataType) -> DataBaseItem {
+        DataBaseItem {
+            destroy_time: self.destroy_time,
+            max_views: self.max_views,
+            custom_url: self.custom_url,
+            uuid: self.uuid,
+            hash: self.hash,
+            short: self.short,
+            secret_hash: self.secret_hash,
+            owner: self.owner,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            stats: ViewStats {
+                views: self.views,
+                last_viewed_at: self.last_viewed_at,
+            },
+            data,
+        }
+    }
+
+    /// the content of this paste read from `entry`, put into the blob store
+    /// when it is too large for `db`
+    fn read_content(
+        &self,
+        db: &dyn Store,
+        entry: &mut impl Read,
+    ) -> Result<DataType, DataBaseErrorType> {
+        if self.kind != Kind::Link && db.blobs().should_store(self.size) {
+            db.blobs().write(&self.hash, entry)?;
+            return Ok(DataType::Blob {
+                digest: self.hash.clone(),
+                size: self.size,
+                text: self.kind == Kind::Text,
+            });
+        }
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        Ok(match self.kind {
+            Kind::Text => match String::from_utf8(content) {
+                Ok(text) => DataType::Text(text),
+                Err(e) => DataType::Binary(e.into_bytes()),
+            },
+            Kind::Link => DataType::ShortLink(String::from_utf8_lossy(&content).into_owned()),
+            Kind::Binary => DataType::Binary(content),
+        })
+    }
+}
+
+/// how many users and pastes an export or import went through
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub users: usize,
+    pub pastes: usize,
+    /// pastes that couldn't be read, or were in the way of others
+    pub skipped: usize,
+}
+
+/// every user and paste read from a database, ready to be written to an
+/// archive once the database has moved on
+pub struct Collected {
+    contents: Vec<(String, Content)>,
+    pastes: Vec<u8>,
+    users: Vec<u8>,
+    summary: Summary,
+}
+
+enum Content {
+    Inline(Vec<u8>),
+    /// a blob, copied from this file as it is written
+    File(PathBuf),
+}
+
+/// write every user and paste of `db` to a tar archive at `path`, which rspb
+/// may keep serving from meanwhile when it is kept in sqlite
+pub fn export(db: &dyn Store, path: &Path) -> Result<Summary, DataBaseErrorType> {
+    let collected = collect(db, |digest| {
+        let path = db.blobs().path(digest);
+        path.metadata().map(|_| path)
+    })?;
+    write(collected, path)
+}
+
+/// read every user and paste of `db`, leaving blobs to be copied from the file
+/// `blob` gives for their digest
+pub fn collect(
+    db: &dyn Store,
+    blob: impl Fn(&str) -> io::Result<PathBuf>,
+) -> Result<Collected, DataBaseErrorType> {
+    let mut summary = Summary::default();
+    let mut contents = Vec::new();
+    let mut pastes = Vec::new();
+    let mut written = HashSet::new();
+    for uuid in db.uuids()? {
+        let item = match db.get_record(uuid) {
+            Ok(item) => item,
+            Err(e) => {
+                log::warn!("skipping paste {}: {}", uuid, e);
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        // the content is kept along with the paste it was read with, as
+        // either may change once it is read
+        let key = item.content_key();
+        if !written.contains(&key) {
+            let name = format!("{}{}", CONTENTS_DIR, key);
+            let content = match item.data.blob_digest() {
+                Some(digest) => match blob(digest) {
+                    Ok(path) => Content::File(path),
+                    Err(e) => {
+                        log::warn!("skipping paste {}: blob {}: {}", uuid, digest, e);
+                        summary.skipped += 1;
+                        continue;
+                    }
+                },
+                None => Content::Inline(item.data.get_data().to_vec()),
+            };
+            contents.push((name, content));
+            written.insert(key);
+        }
+        serde_json::to_writer(&mut pastes, &Paste::from(&item))?;
+        pastes.push(b'\n');
+        summary.pastes += 1;
+    }
+    let mut users = Vec::new();
+    for user in db.users()? {
+        serde_json::to_writer(&mut users, &user)?;
+        users.push(b'\n');
+        summary.users += 1;
+    }
+    Ok(Collected {
+        contents,
+        pastes,
+        users,
+        summary,
+    })
+}
+
+/// write what was collected to a tar archive at `path`
+pub fn write(collected: Collected, path: &Path) -> Result<Summary, DataBaseErrorType> {
+    let mut archive = tar::Builder::new(File::create(path)?);
+    let version = FORMAT.to_string();
+    append(&mut archive, VERSION_ENTRY, version.as_bytes())?;
+    for (name, content) in &collected.contents {
+        match content {
+            Content::Inline(data) => append(&mut archive, name, data)?,
+            Content::File(path) => archive.append_file(name, &mut File::open(path)?)?,
+        }
+    }
+    append(&mut archive, PASTES_ENTRY, &collected.pastes)?;
+    append(&mut archive, USERS_ENTRY, &collected.users)?;
+    archive.into_inner()?.sync_all()?;
+    Ok(collected.summary)
+}
+
+fn append(
+    archive: &mut tar::Builder<File>,
+    name: &str,
+    data: &[u8],
+) -> Result<(), DataBaseErrorType> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    archive.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+/// add the users and pastes of the tar archive at `path` to `db`, with their
+/// uuids, short ids and custom urls, skipping those taken already
+pub fn import(db: &dyn Store, path: &Path) -> Result<Summary, DataBaseErrorType> {
+    let mut summary = Summary::default();
+    // the lists come after the contents, so they are read first
+    let mut pending: HashMap<String, Vec<Paste>> = HashMap::new();
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        match name.as_str() {
+            VERSION_ENTRY => {
+                let mut version = String::new();
+                entry.read_to_string(&mut version)?;
+                if version.trim() != FORMAT.to_string() {
+                    return Err(DataBaseErrorType::Serialization(format!(
+                        "archive format {} isn't supported",
+                        version.trim()
+                    )));
+                }
+            }
+            USERS_ENTRY => {
+                for line in BufReader::new(entry).lines() {
+                    let user: User = serde_json::from_str(&line?)?;
+                    if db.add_user(&user)? {
+                        summary.users += 1;
+                    } else {
+                        log::warn!("user {} exists already, skipping", user.name);
+                    }
+                }
+            }
+            PASTES_ENTRY => {
+                for line in BufReader::new(entry).lines() {
+                    let paste: Paste = serde_json::from_str(&line?)?;
+                    pending
+                        .entry(paste.content.clone())
+                        .or_default()
+                        .push(paste);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let key = match name.strip_prefix(CONTENTS_DIR) {
+            Some(key) => key,
+            None => continue,
+        };
+        let pastes = match pending.remove(key) {
+            Some(pastes) => pastes,
+            None => {
+                log::warn!("{} belongs to no paste, skipping", name);
+                continue;
+            }
+        };
+        // pastes already there may use the blob this content goes to
+        let _blobs = db.blobs().lock();
+        let had_blob = db.blobs().path(&pastes[0].hash).exists();
+        let data = match pastes[0].read_content(db, &mut entry) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("skipping the pastes of {}: {}", name, e);
+                summary.skipped += pastes.len();
+                continue;
+            }
+        };
+        let mut restored = false;
+        for paste in pastes {
+            restored |= restore(db, paste.into_item(data.clone()), &mut summary);
+        }
+        if let Some(digest) = data.blob_digest().filter(|_| !restored && !had_blob) {
+            if let Err(e) = db.blobs().remove(digest) {
+                log::warn!("removing blob {} failed: {}", digest, e);
+            }
+        }
+    }
+    for paste in pending.values().flatten() {
+        log::warn!(
+            "paste {} has no content in the archive, skipping",
+            paste.uuid
+        );
+        summary.skipped += 1;
+    }
+    db.flush()?;
+    Ok(summary)
+}
+
+/// add `item` to `db`, returning whether it was added
+fn restore(db: &dyn Store, item: DataBaseItem, summary: &mut Summary) -> bool {
+    match db.restore_record(&item) {
+        Ok(()) => {
+            summary.pastes += 1;
+            return true;
+        }
+        Err(DataBaseErrorType::Existed(existing)) => log::warn!(
+            "paste {} is in the way of {}, skipping",
+            existing.uuid,
+            item.uuid
+        ),
+        Err(e) => log::warn!("skipping paste {}: {}", item.uuid, e),
+    }
+    summary.skipped += 1;
+    false
+}
@@ -0,0 +1,41 @@
+use warp::Rejection;
+
+use crate::error::Error;
+use crate::model::{self, User};
+
+/// name the holder of the admin key from the config is known by
+pub const ADMIN: &str = "admin";
+
+/// the user an `Authorization: Bearer <api key>` header belongs to, nobody
+/// when the header is missing
+pub async fn caller(
+    authorization: Option<String>,
+    db: model::Db,
+    admin_key: Option<blake3::Hash>,
+) -> Result<Option<User>, Rejection> {
+    let authorization = match authorization {
+        Some(authorization) => authorization,
+        None => return Ok(None),
+    };
+    let key = match authorization.strip_prefix("Bearer ") {
+        Some(key) => key.trim(),
+        None => return Err(warp::reject::custom(invalid_key())),
+    };
+    let key_hash = blake3::hash(key.as_bytes());
+    // comparing blake3 hashes takes constant time
+    if admin_key == Some(key_hash) {
+        return Ok(Some(User {
+            name: String::from(ADMIN),
+            key_hash: String::from(key_hash.to_hex().as_str()),
+            admin: true,
+        }));
+    }
+    match db.find_user(key_hash.to_hex().as_str()) {
+        Ok(user) => Ok(Some(user)),
+        Err(_) => Err(warp::reject::custom(invalid_key())),
+    }
+}
+
+fn invalid_key() -> Error {
+    Error::Unauthorized(String::from("invalid api key"))
+}
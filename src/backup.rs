@@ -0,0 +1,277 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, TryLockError};
+use std::time::Duration;
+use std::{fs, io};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::archive::{self, Summary};
+use crate::blob::BlobStore;
+use crate::model::{Cursor, DataBaseErrorType, DataBaseItem, DataType, Db, Store, User};
+
+const PREFIX: &str = "rspb-";
+const SUFFIX: &str = ".tar";
+
+/// a store whose writes can be paused, so that a snapshot sees every paste and
+/// user as they were at one point in time. views are still counted meanwhile
+pub struct Pausable {
+    store: Db,
+    /// shared by writers, held alone while writes are paused
+    writes: RwLock<()>,
+}
+
+impl Pausable {
+    pub fn new(store: Db) -> Self {
+        Pausable {
+            store,
+            writes: RwLock::new(()),
+        }
+    }
+
+    /// run `f` on the store with writes waiting until it returns
+    pub fn paused<T>(&self, f: impl FnOnce(&dyn Store) -> T) -> T {
+        let _paused = self.writes.write().unwrap_or_else(|e| e.into_inner());
+        f(self.store.as_ref())
+    }
+
+    /// writes come from request handlers, so a worker waiting for a pause to
+    /// end hands its other tasks to another thread
+    fn writing(&self) -> RwLockReadGuard<'_, ()> {
+        match self.writes.try_read() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => tokio::task::block_in_place(|| {
+                self.writes.read().unwrap_or_else(|e| e.into_inner())
+            }),
+        }
+    }
+}
+
+impl Store for Pausable {
+    fn blobs(&self) -> &BlobStore {
+        self.store.blobs()
+    }
+
+    fn add_record(&self, data: &mut DataBaseItem) -> Result<(), DataBaseErrorType> {
+        let _writing = self.writing();
+        self.store.add_record(data)
+    }
+
+    fn restore_record(&self, data: &DataBaseItem) -> Result<(), DataBaseErrorType> {
+        let _writing = self.writing();
+        self.store.restore_record(data)
+    }
+
+    fn query_record(&self, key: &str) -> Result<DataBaseItem, DataBaseErrorType> {
+        self.store.query_record(key)
+    }
+
+    fn get_record(&self, key: Uuid) -> Result<DataBaseItem, DataBaseErrorType> {
+        self.store.get_record(key)
+    }
+
+    fn replace_data(
+        &self,
+        key: Uuid,
+        value: DataType,
+        hash: blake3::Hash,
+    ) -> Result<Option<DataType>, DataBaseErrorType> {
+        let _writing = self.writing();
+        self.store.replace_data(key, value, hash)
+    }
+
+    fn set_expiry(
+        &self,
+        key: Uuid,
+        destroy_time: Option<DateTime<Utc>>,
+    ) -> Result<(), DataBaseErrorType> {
+        let _writing = self.writing();
+        self.store.set_expiry(key, destroy_time)
+    }
+
+    fn remove_record(&self, key: Uuid) -> Result<Option<DataType>, DataBaseErrorType> {
+        let _writing = self.writing();
+        self.store.remove_record(key)
+    }
+
+    fn expired_records(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        self.store.expired_records(now)
+    }
+
+    fn record_view(&self, key: Uuid, at: DateTime<Utc>) -> Result<u64, DataBaseErrorType> {
+        self.store.record_view(key, at)
+    }
+
+    fn list_records(
+        &self,
+        owner: &str,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<DataBaseItem>, DataBaseErrorType> {
+        self.store.list_records(owner, after, limit)
+    }
+
+    fn add_user(&self, user: &User) -> Result<bool, DataBaseErrorType> {
+        let _writing = self.writing();
+        self.store.add_user(user)
+    }
+
+    fn find_user(&self, key_hash: &str) -> Result<User, DataBaseErrorType> {
+        self.store.find_user(key_hash)
+    }
+
+    fn uuids(&self) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        self.store.uuids()
+    }
+
+    fn users(&self) -> Result<Vec<User>, DataBaseErrorType> {
+        self.store.users()
+    }
+
+    fn flush(&self) -> Result<(), DataBaseErrorType> {
+        self.store.flush()
+    }
+}
+
+/// snapshots of the database taken while rspb keeps serving, each an archive
+/// `rspb import` can restore
+pub struct Snapshots {
+    db: Arc<Pausable>,
+    pub dir: PathBuf,
+    /// how many snapshots are kept, 0 keeps them all
+    pub retain: usize,
+    /// held while a snapshot is taken, so that two don't overlap
+    running: Mutex<()>,
+}
+
+#[derive(Debug)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub summary: Summary,
+}
+
+impl Snapshots {
+    pub fn new(db: Arc<Pausable>, dir: PathBuf, retain: usize) -> Self {
+        Snapshots {
+            db,
+            dir,
+            retain,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// take a snapshot, unless one is being taken already. writes wait while
+    /// the pastes and users are read, so that it is consistent, the blobs are
+    /// copied once they are done
+    pub fn take(&self) -> Result<Option<Snapshot>, DataBaseErrorType> {
+        let _running = match self.running.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        };
+        fs::create_dir_all(&self.dir)?;
+        // names sort by the time they were taken at
+        let name = format!(
+            "{}{}{}",
+            PREFIX,
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            SUFFIX
+        );
+        let path = self.dir.join(&name);
+        // half written snapshots don't count as one
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let staged = Staged::new(self.db.blobs(), &name)?;
+        let summary = match self
+            .db
+            .paused(|db| archive::collect(db, |digest| staged.link(digest)))
+            .and_then(|collected| archive::write(collected, &tmp))
+        {
+            Ok(summary) => summary,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        drop(staged);
+        fs::rename(&tmp, &path)?;
+        self.prune()?;
+        Ok(Some(Snapshot { path, summary }))
+    }
+
+    /// remove all but the newest `retain` snapshots
+    fn prune(&self) -> io::Result<()> {
+        if self.retain == 0 {
+            return Ok(());
+        }
+        let mut names: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(PREFIX) && name.ends_with(SUFFIX))
+            .collect();
+        names.sort();
+        let excess = names.len().saturating_sub(self.retain);
+        for name in &names[..excess] {
+            fs::remove_file(self.dir.join(name))?;
+            info!("removed snapshot {}", name);
+        }
+        Ok(())
+    }
+}
+
+/// hard links to the blobs of a snapshot, so that they outlive their pastes
+/// being deleted until they are copied
+struct Staged<'a> {
+    blobs: &'a BlobStore,
+    dir: PathBuf,
+}
+
+impl<'a> Staged<'a> {
+    /// kept next to the blobs, as links can't cross filesystems
+    fn new(blobs: &'a BlobStore, name: &str) -> io::Result<Self> {
+        let dir = blobs.dir.with_file_name(format!(".{}.blobs", name));
+        fs::create_dir_all(&dir)?;
+        Ok(Staged { blobs, dir })
+    }
+
+    fn link(&self, digest: &str) -> io::Result<PathBuf> {
+        let path = self.dir.join(digest);
+        if !path.exists() {
+            let blob = self.blobs.path(digest);
+            if fs::hard_link(&blob, &path).is_err() {
+                fs::copy(&blob, &path)?;
+            }
+        }
+        Ok(path)
+    }
+}
+
+impl Drop for Staged<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("removing {} failed: {}", self.dir.display(), e);
+        }
+    }
+}
+
+pub async fn run(snapshots: Arc<Snapshots>, interval: u64) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    // the first tick is right away, a restart shouldn't take a snapshot
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let snapshots = snapshots.clone();
+        match tokio::task::spawn_blocking(move || snapshots.take()).await {
+            Ok(Ok(Some(snapshot))) => info!(
+                "took snapshot {} of {} pastes",
+                snapshot.path.display(),
+                snapshot.summary.pastes
+            ),
+            // one asked for through the api is being taken
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => warn!("taking a snapshot failed: {}", e),
+            Err(e) => warn!("snapshot task failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::model::DataType;
+use crate::upload::SpooledFile;
+
+/// content addressed storage for large pastes, one file per blake3 digest
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    pub dir: PathBuf,
+    /// pastes larger than this many bytes are kept here instead of in sled
+    pub threshold: u64,
+    /// held while a blob gains its first or loses its last reference, so that
+    /// no paste takes a reference to a blob being removed
+    refs: Arc<Mutex<()>>,
+}
+
+impl BlobStore {
+    pub fn new(dir: PathBuf, threshold: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(BlobStore {
+            dir,
+            threshold,
+            refs: Arc::new(Mutex::new(())),
+        })
+    }
+
+    /// hold this while storing a blob and taking a reference to it, or while
+    /// dropping a reference and releasing the blob
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.refs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn should_store(&self, size: u64) -> bool {
+        size > self.threshold
+    }
+
+    /// blobs are spread over subdirectories named by the first two hex digits
+    pub fn path(&self, digest: &str) -> PathBuf {
+        self.dir.join(&digest[..2.min(digest.len())]).join(digest)
+    }
+
+    /// move a spooled upload into the store, keeping an existing blob as is,
+    /// returning whether the blob is new
+    pub fn insert(&self, content: &SpooledFile) -> io::Result<bool> {
+        let path = self.path(&content.hash.to_hex());
+        if path.exists() {
+            return Ok(false);
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if std::fs::rename(&content.path, &path).is_err() {
+            // the spool may live on another filesystem
+            let tmp = path.with_extension("tmp");
+            std::fs::copy(&content.path, &tmp)?;
+            std::fs::rename(&tmp, &path)?;
+        }
+        Ok(true)
+    }
+
+    pub async fn open(&self, digest: &str) -> io::Result<tokio::fs::File> {
+        tokio::fs::File::open(self.path(digest)).await
+    }
+
+    /// copy `content` into the store under `digest`, failing when it doesn't
+    /// hash to it
+    pub fn write(&self, digest: &str, content: &mut impl Read) -> io::Result<()> {
+        let path = self.path(digest);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        let copied = (|| {
+            let mut file = std::fs::File::create(&tmp)?;
+            let mut hasher = blake3::Hasher::new();
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = content.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+                file.write_all(&buf[..n])?;
+            }
+            if hasher.finalize().to_hex().as_str() != digest {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("content of blob {} doesn't match its digest", digest),
+                ));
+            }
+            Ok(())
+        })();
+        match copied {
+            Ok(()) => std::fs::rename(&tmp, &path),
+            Err(e) => {
+                // a partial copy is no blob
+                let _ = std::fs::remove_file(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn remove(&self, digest: &str) -> io::Result<()> {
+        std::fs::remove_file(self.path(digest))
+    }
+
+    /// remove the blob of content no paste refers to anymore
+    pub fn release(&self, data: &DataType) {
+        if let Some(digest) = data.blob_digest() {
+            if let Err(e) = self.remove(digest) {
+                log::warn!("removing blob {} failed: {}", digest, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+
+use crate::config::{Backend, Config};
+
+/// a pastebin, serving pastes unless told otherwise
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// config file to read, `config.yaml` when it exists otherwise
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// database of the backend, the sled directory or the sqlite file
+    #[arg(long, global = true)]
+    pub db_path: Option<PathBuf>,
+    /// address to listen on, like 127.0.0.1:3030 or [::1]:3030
+    #[arg(long, global = true)]
+    pub listen: Option<SocketAddr>,
+    /// markdown file rendered as the front page
+    #[arg(long, global = true)]
+    pub help_page: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// serve pastes
+    Serve,
+    /// check a sled database while rspb is stopped, exiting with 1 while
+    /// problems are left
+    Fsck {
+        /// fix what was found
+        #[arg(long)]
+        repair: bool,
+    },
+    /// write every user and paste to a tar archive
+    Export { file: PathBuf },
+    /// add the users and pastes of a tar archive
+    Import { file: PathBuf },
+    /// purge expired pastes once
+    Gc,
+    /// print how many pastes and users there are
+    Stats,
+}
+
+impl Cli {
+    /// let the flags given override `config`
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(path) = &self.db_path {
+            match config.backend {
+                Backend::Sled => config.db_path = path.clone(),
+                Backend::Sqlite => config.sqlite_path = path.clone(),
+                Backend::Memory => {}
+            }
+        }
+        if let Some(addr) = self.listen {
+            config.ip = addr.ip();
+            config.port = addr.port();
+        }
+        if let Some(path) = &self.help_page {
+            config.help_page = Some(path.clone());
+        }
+        // the readme of an install sits next to its config
+        if config.help_page.is_none() {
+            let dir = self.config.as_deref().and_then(Path::parent);
+            config.help_page = Some(dir.unwrap_or(Path::new("")).join("README.md"));
+        }
+    }
+}
@@ -1,16 +1,76 @@
 use std::{
+    fmt, io,
     net::{IpAddr, Ipv6Addr},
-    usize,
+    path::{Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::{
+    controller::PageSize,
+    ttl::{Retention, Ttl},
+};
+
+/// prefix of the environment variables overriding settings, like `RSPB_PORT`
+const ENV_PREFIX: &str = "RSPB_";
 
 #[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    Sled,
+    Sqlite,
+    /// nothing is persisted, pastes are gone after a restart
+    Memory,
+}
+
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub ip: std::net::IpAddr,
     pub port: u16,
+    /// where rspb is reached from outside, like `https://pb.example.com`, links
+    /// point at the host a request was sent to over http when unset
+    pub public_url: Option<String>,
+    /// log level, or a spec like `info,rspb=debug`, which `RUST_LOG` overrides
+    pub log_level: String,
     pub max_length: u64,
+    /// pastes on a page of a user's listing when it asks for no `limit`
+    pub default_page_size: usize,
+    /// most pastes on a page of a user's listing
+    pub max_page_size: usize,
     pub db_cache_capacity: u64,
+    pub backend: Backend,
+    /// database directory of the sled backend
+    pub db_path: PathBuf,
+    /// database file of the sqlite backend
+    pub sqlite_path: PathBuf,
+    /// seconds between two runs of the expired paste sweeper, 0 disables it
+    pub sweep_interval: u64,
+    /// directory uploads are streamed to before being stored
+    pub spool_dir: PathBuf,
+    /// directory large pastes are stored in, one file per content digest
+    pub blob_dir: PathBuf,
+    /// pastes larger than this many bytes go to `blob_dir` instead of the database
+    pub blob_threshold: u64,
+    /// api key that can create users and manage every paste
+    pub admin_key: Option<String>,
+    /// how long pastes uploaded without `sunset` live, forever when unset
+    pub default_ttl: Option<Ttl>,
+    /// shortest `sunset` accepted
+    pub min_ttl: Option<Ttl>,
+    /// longest `sunset` accepted, also how long pastes uploaded without one
+    /// live when `default_ttl` is unset
+    pub max_ttl: Option<Ttl>,
+    /// directory snapshots of the database are written to
+    pub backup_dir: PathBuf,
+    /// seconds between two scheduled snapshots, 0 disables them
+    pub backup_interval: u64,
+    /// how many snapshots are kept, 0 keeps them all
+    pub backup_retain: usize,
+    /// markdown file rendered as the front page, `README.md` beside the
+    /// config file when unset
+    pub help_page: Option<PathBuf>,
 }
 
 impl Default for Config {
@@ -18,18 +78,160 @@ impl Default for Config {
         Config {
             ip: IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
             port: 3999,
+            public_url: None,
+            log_level: String::from("info"),
             max_length: 5_000_000,
+            default_page_size: 50,
+            max_page_size: 500,
             db_cache_capacity: 5_000_000,
+            backend: Backend::Sled,
+            db_path: PathBuf::from("db"),
+            sqlite_path: PathBuf::from("db.sqlite3"),
+            sweep_interval: 60,
+            spool_dir: PathBuf::from("spool"),
+            blob_dir: PathBuf::from("blobs"),
+            blob_threshold: 1_000_000,
+            admin_key: None,
+            default_ttl: None,
+            min_ttl: None,
+            max_ttl: None,
+            backup_dir: PathBuf::from("backups"),
+            backup_interval: 0,
+            backup_retain: 7,
+            help_page: None,
         }
     }
 }
 
 impl Config {
-    pub async fn load(path: Option<&str>) -> Option<Self> {
-        let file = tokio::fs::read_to_string(path.unwrap_or("config.yaml")).await;
-        if let Ok(str) = file {
-            return Some(serde_yaml::from_str(&str).unwrap());
+    pub fn page_size(&self) -> PageSize {
+        PageSize {
+            default: self.default_page_size,
+            max: self.max_page_size,
+        }
+    }
+
+    pub fn retention(&self) -> Retention {
+        Retention {
+            default: self.default_ttl,
+            min: self.min_ttl,
+            max: self.max_ttl,
+        }
+    }
+
+    /// the settings of the file at `path`, or of `config.yaml` when there is
+    /// one, each overridden by its `RSPB_*` environment variable. variables
+    /// naming no setting are returned, as others like kubernetes' `RSPB_PORT_*`
+    /// share the prefix
+    pub async fn load(path: Option<&Path>) -> Result<(Self, Vec<String>), ConfigError> {
+        let defaults = match serde_yaml::to_value(Config::default()) {
+            Ok(Value::Mapping(defaults)) => defaults,
+            _ => Mapping::new(),
+        };
+        let mut settings = match path {
+            Some(path) => read(path).await?,
+            None => match read(Path::new("config.yaml")).await {
+                Err(ConfigError::Read(_, e)) if e.kind() == io::ErrorKind::NotFound => {
+                    Mapping::new()
+                }
+                settings => settings?,
+            },
+        };
+        let mut vars: Vec<_> = std::env::vars_os()
+            .filter(|(var, _)| var.to_string_lossy().starts_with(ENV_PREFIX))
+            .collect();
+        vars.sort();
+        let mut ignored = Vec::new();
+        for (var, raw) in vars {
+            let var = var.to_string_lossy().into_owned();
+            let key = Value::String(var[ENV_PREFIX.len()..].to_lowercase());
+            let default = match defaults.get(&key) {
+                Some(default) => default,
+                None => {
+                    ignored.push(var);
+                    continue;
+                }
+            };
+            let raw = raw
+                .into_string()
+                .map_err(|_| ConfigError::Invalid(format!("{} isn't valid unicode", var)))?;
+            let value = if raw.is_empty() {
+                Value::Null
+            } else if default.is_string() || default.is_null() {
+                // paths, keys and durations are taken as they are
+                Value::String(raw)
+            } else {
+                serde_yaml::from_str(&raw).map_err(|e| ConfigError::Parse(var.clone(), e))?
+            };
+            // checked on its own, so that a wrong value is blamed on its variable
+            let mut alone = defaults.clone();
+            alone.insert(key.clone(), value.clone());
+            serde_yaml::from_value::<Config>(Value::Mapping(alone))
+                .map_err(|e| ConfigError::Parse(var, e))?;
+            settings.insert(key, value);
+        }
+        let config = serde_yaml::from_value(Value::Mapping(settings))
+            .map_err(|e| ConfigError::Parse(String::from("settings"), e))?;
+        Ok((config, ignored))
+    }
+
+    /// settings that can be read but don't make sense together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_length == 0 {
+            return Err(ConfigError::Invalid(String::from(
+                "max_length must be positive",
+            )));
+        }
+        if self.default_page_size == 0 || self.default_page_size > self.max_page_size {
+            return Err(ConfigError::Invalid(format!(
+                "default_page_size {} must be positive and at most max_page_size {}",
+                self.default_page_size, self.max_page_size
+            )));
+        }
+        let scheme = |url: &&String| url.starts_with("http://") || url.starts_with("https://");
+        if let Some(url) = self.public_url.as_ref().filter(|url| !scheme(url)) {
+            return Err(ConfigError::Invalid(format!(
+                "public_url {} must start with http:// or https://",
+                url
+            )));
+        }
+        flexi_logger::LogSpecification::parse(&self.log_level)
+            .map_err(|e| ConfigError::Invalid(format!("log_level: {}", e)))?;
+        self.retention().validate().map_err(ConfigError::Invalid)
+    }
+}
+
+/// the settings of a config file, checked against `Config`
+async fn read(path: &Path) -> Result<Mapping, ConfigError> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    let source = path.display().to_string();
+    // a file of only comments holds no settings
+    let blank = |line: &str| line.trim().is_empty() || line.trim_start().starts_with('#');
+    if text.lines().all(blank) {
+        return Ok(Mapping::new());
+    }
+    // parsed as `Config` first for errors that point at the line
+    serde_yaml::from_str::<Config>(&text).map_err(|e| ConfigError::Parse(source.clone(), e))?;
+    serde_yaml::from_str(&text).map_err(|e| ConfigError::Parse(source, e))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// the config file can't be read
+    Read(PathBuf, io::Error),
+    /// a setting of the named file or variable has the wrong type
+    Parse(String, serde_yaml::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "can't read {}: {}", path.display(), e),
+            ConfigError::Parse(source, e) => write!(f, "{}: {}", source, e),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
         }
-        None
     }
 }
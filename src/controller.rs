@@ -1,49 +1,262 @@
-use std::{collections::HashMap, unreachable};
+use std::{collections::HashMap, fmt, io, sync::Arc};
 
-use bytes::BufMut;
-use chrono::{prelude::*, Duration};
-use futures::TryStreamExt;
+use chrono::prelude::*;
 use log::info;
+use serde::Serialize;
 
-use model::{add_record, delete_record, DataBaseItem};
-use warp::multipart::FormData;
-use warp::{http, hyper::Uri, multipart::Part, path::FullPath};
+use model::DataBaseItem;
+use tokio_util::io::ReaderStream;
+use warp::{http, hyper::Body, hyper::Uri, path::FullPath};
 use warp::{Rejection, Reply};
 
 use crate::{
+    auth,
+    backup::Snapshots,
+    error::{Error, Formatted},
     highlighter::highlight_lines,
-    model::{self, DataType},
+    model::{self, DataType, User},
+    ttl::Retention,
+    upload::{SpooledFile, UploadForm},
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Text,
+    Json,
+}
+
+impl ResponseFormat {
+    /// json when asked for with `Accept: application/json` or `?format=json`,
+    /// plain text otherwise so that curl output stays readable
+    pub fn negotiate(accept: Option<String>, query: HashMap<String, String>) -> Self {
+        let json_query = query.get("format").map(|f| f == "json").unwrap_or(false);
+        let json_accept = accept
+            .map(|a| a.contains("application/json"))
+            .unwrap_or(false);
+        if json_query || json_accept {
+            ResponseFormat::Json
+        } else {
+            ResponseFormat::Text
+        }
+    }
+
+    fn reply<T: Serialize>(
+        self,
+        text: String,
+        json: &T,
+        status: http::StatusCode,
+    ) -> warp::reply::Response {
+        match self {
+            ResponseFormat::Text => warp::reply::with_status(text, status).into_response(),
+            ResponseFormat::Json => {
+                warp::reply::with_status(warp::reply::json(json), status).into_response()
+            }
+        }
+    }
+
+    /// `error`, to be reported in this format
+    pub fn reject(self, error: Error) -> Rejection {
+        warp::reject::custom(Formatted(error, self))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
 enum UploadStatus {
-    Failed,
     Created,
     Existed,
+    Updated,
+    Deleted,
 }
 
-impl ToString for UploadStatus {
-    fn to_string(&self) -> String {
+impl fmt::Display for UploadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            UploadStatus::Created => String::from("created"),
-            UploadStatus::Existed => String::from("existed"),
-            UploadStatus::Failed => String::from("failed"),
+            UploadStatus::Created => write!(f, "created"),
+            UploadStatus::Existed => write!(f, "existed"),
+            UploadStatus::Updated => write!(f, "updated"),
+            UploadStatus::Deleted => write!(f, "deleted"),
         }
     }
 }
 
+#[derive(Serialize)]
 struct UploadResponse {
-    date: String,
+    date: DateTime<Utc>,
     digest: String,
     short: String,
     size: usize,
     status: UploadStatus,
     url: String,
     uuid: String,
+    expiry: Option<DateTime<Utc>>,
+    max_views: Option<u64>,
+    /// needed to update or delete the paste, only given out when it is created
+    secret: Option<String>,
+}
+
+#[derive(Serialize)]
+struct DeleteResponse {
+    status: UploadStatus,
+    uuid: String,
+}
+
+#[derive(Serialize)]
+struct UserResponse {
+    status: UploadStatus,
+    name: String,
+    key: String,
+}
+
+impl fmt::Display for UserResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "key: {}", self.key)
+    }
+}
+
+#[derive(Serialize)]
+struct BackupResponse {
+    status: UploadStatus,
+    snapshot: String,
+    users: usize,
+    pastes: usize,
+    /// pastes that couldn't be read
+    skipped: usize,
+}
+
+impl fmt::Display for BackupResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "snapshot: {}", self.snapshot)?;
+        writeln!(f, "users: {}", self.users)?;
+        writeln!(f, "pastes: {}", self.pastes)
+    }
+}
+
+/// what the listing of a user's pastes shows about each of them
+#[derive(Serialize)]
+struct PasteMetadata {
+    uuid: String,
+    short: String,
+    custom_url: Option<String>,
+    size: u64,
+    digest: String,
+    expiry: Option<DateTime<Utc>>,
+    max_views: Option<u64>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    last_viewed_at: Option<DateTime<Utc>>,
+    views: u64,
+}
+
+impl From<DataBaseItem> for PasteMetadata {
+    fn from(item: DataBaseItem) -> Self {
+        PasteMetadata {
+            uuid: item.uuid.to_string(),
+            size: item.data.size(),
+            short: item.short,
+            custom_url: item.custom_url,
+            digest: item.hash,
+            expiry: item.destroy_time,
+            max_views: item.max_views,
+            created_at: item.created_at,
+            updated_at: item.updated_at,
+            last_viewed_at: item.stats.last_viewed_at,
+            views: item.stats.views,
+        }
+    }
+}
+
+impl PasteMetadata {
+    /// everything known about the paste, one `name: value` per line
+    fn details(&self) -> String {
+        let never = String::from("never");
+        let mut lines = vec![
+            format!("uuid: {}", self.uuid),
+            format!("short: {}", self.short),
+        ];
+        if let Some(url) = &self.custom_url {
+            lines.push(format!("custom url: {}", url));
+        }
+        lines.push(format!("size: {}", self.size));
+        lines.push(format!("digest: {}", self.digest));
+        lines.push(format!(
+            "expiry: {}",
+            self.expiry.map_or_else(|| never.clone(), |t| t.to_string())
+        ));
+        if let Some(views) = self.max_views {
+            lines.push(format!("max views: {}", views));
+        }
+        lines.push(format!("created at: {}", self.created_at));
+        lines.push(format!("updated at: {}", self.updated_at));
+        lines.push(format!(
+            "last viewed at: {}",
+            self.last_viewed_at.map_or(never, |t| t.to_string())
+        ));
+        lines.push(format!("views: {}", self.views));
+        lines.join("\n") + "\n"
+    }
+}
+
+impl fmt::Display for PasteMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.custom_url.as_deref().unwrap_or(&self.short),
+            self.size,
+            self.created_at,
+            self.uuid
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    pastes: Vec<PasteMetadata>,
+    /// pass as `cursor` to get the next page
+    next_cursor: Option<String>,
+}
+
+impl fmt::Display for ListResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for paste in &self.pastes {
+            write!(f, "{}", paste)?;
+        }
+        if let Some(cursor) = &self.next_cursor {
+            writeln!(f, "next: {}", cursor)?;
+        }
+        Ok(())
+    }
+}
+
+/// listing cursors are the creation time in nanoseconds and the uuid of the
+/// last paste of a page
+fn encode_cursor(item: &PasteMetadata) -> String {
+    // times past 2262 don't fit, they sort last
+    let nanos = item.created_at.timestamp_nanos_opt().unwrap_or(i64::MAX);
+    format!("{}-{}", nanos, item.uuid)
 }
 
-impl ToString for UploadResponse {
-    fn to_string(&self) -> String {
-        return format!(
+fn decode_cursor(cursor: &str) -> Option<model::Cursor> {
+    let (nanos, uuid) = cursor.split_once('-')?;
+    let nanos = nanos.parse::<i64>().ok()?;
+    let uuid = uuid::Uuid::parse_str(uuid).ok()?;
+    Some((Utc.timestamp_nanos(nanos), uuid))
+}
+
+/// how many pastes a page of a listing holds
+#[derive(Copy, Clone, Debug)]
+pub struct PageSize {
+    /// for listings that ask for no `limit`
+    pub default: usize,
+    pub max: usize,
+}
+
+impl fmt::Display for UploadResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
             "date: {}
 digest: {}
 short: {}
@@ -52,148 +265,230 @@ url: {}
 status: {}
 uuid: {}
 ",
-            self.date,
-            self.digest,
-            self.short,
-            self.size,
-            self.url,
-            self.status.to_string(),
-            self.uuid,
-        );
+            self.date, self.digest, self.short, self.size, self.url, self.status, self.uuid,
+        )?;
+        if let Some(views) = self.max_views {
+            writeln!(f, "max views: {}", views)?;
+        }
+        if let Some(secret) = &self.secret {
+            writeln!(f, "secret: {}", secret)?;
+        }
+        Ok(())
+    }
+}
+
+/// the paste `key` points at, as long as `secret` is the one it was created
+/// with or the caller owns it; `key` is either its uuid or one of its public
+/// keys
+fn authorize(
+    db: &model::Db,
+    key: &str,
+    secret: Option<String>,
+    caller: Option<User>,
+) -> Result<DataBaseItem, Error> {
+    let item = match uuid::Uuid::parse_str(key) {
+        Ok(id) => db.get_record(id),
+        Err(_) => db.query_record(key),
+    }
+    .map_err(|_| Error::NotFound(format!("{} not found", key)))?;
+    if caller.map(|user| user.manages(&item)).unwrap_or(false) {
+        return Ok(item);
+    }
+    match secret {
+        Some(secret) if item.check_secret(&secret) => Ok(item),
+        _ => Err(Error::Forbidden(format!("wrong secret for {}", key))),
     }
 }
 
-async fn read_multipart_form(parts: Vec<Part>) -> HashMap<String, Vec<u8>> {
-    let mut res = std::collections::HashMap::new();
-    for p in parts {
-        let name = String::from(p.name());
-        let value = p
-            .stream()
-            .try_fold(Vec::new(), |mut vec, data| {
-                vec.put(data);
-                async move { Ok(vec) }
-            })
-            .await
-            .unwrap_or_default();
-        res.insert(name, value);
+/// turn spooled content into what gets stored, leaving large uploads in the
+/// spool for `store_blob` instead of reading them into memory
+async fn store_content(
+    db: &model::Db,
+    content: &SpooledFile,
+    is_short_link: bool,
+) -> io::Result<Option<DataType>> {
+    if !is_short_link && db.blobs().should_store(content.size) {
+        return Ok(Some(DataType::Blob {
+            digest: content.hash.to_hex().to_string(),
+            size: content.size,
+            text: content.utf8,
+        }));
     }
-    res
+    let bytes = content.read().await?;
+    Ok(DataType::from_bytes(bytes, Some(is_short_link)))
 }
 
+/// move the spooled `content` to the blob store when `data` is kept there,
+/// returning whether the blob is new. the caller holds the blob lock until the
+/// paste took its reference
+fn store_blob(db: &model::Db, content: &SpooledFile, data: &DataType) -> Result<bool, Error> {
+    match data {
+        DataType::Blob { .. } => db
+            .blobs()
+            .insert(content)
+            .map_err(|e| Error::Internal(format!("storing upload failed: {}", e))),
+        _ => Ok(false),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_upload(
     path: FullPath,
-    form: FormData,
-    db: model::DataTrees,
-    url: String,
+    data: UploadForm,
+    db: model::Db,
+    base_url: String,
     custom_url: Option<String>,
+    caller: Option<User>,
+    retention: Retention,
+    format: ResponseFormat,
 ) -> Result<warp::reply::Response, Rejection> {
-    let parts: Vec<Part> = form.try_collect().await.map_err(|e| {
-        eprintln!("form error: {}", e);
-        warp::reject::reject()
-    })?;
-    let data = read_multipart_form(parts).await;
-    let content = data.get("c").or_else(|| data.get("content"));
-    let destroy = data.get("sunset");
     let now: DateTime<Utc> = Utc::now();
-
-    if content.is_none() {
-        return Ok(
-            warp::reply::with_status(String::from("error"), http::StatusCode::BAD_REQUEST)
-                .into_response(),
-        );
-    }
-    let data = DataType::from_bytes(content.unwrap().clone(), Some(path.as_str() == "/u"));
-    if data.is_none() {
-        return Ok(
-            warp::reply::with_status(String::from("error"), http::StatusCode::BAD_REQUEST)
-                .into_response(),
-        );
-    }
-    let data = data.unwrap();
-    let mut item: DataBaseItem = DataBaseItem::new(data, custom_url.clone(), None);
-
-    if let Some(seconds) = destroy {
-        let seconds = String::from(String::from_utf8_lossy(seconds)).parse::<i64>();
-        match seconds {
-            Ok(seconds) => {
-                item.destroy_time = Some(now + Duration::seconds(seconds));
-            }
-            Err(err) => {
-                return Ok(warp::reply::with_status(
-                    err.to_string(),
-                    http::StatusCode::BAD_REQUEST,
+    let sunset = data.get("sunset").map(|v| String::from_utf8_lossy(v));
+    let destroy_time = match retention.expiry(sunset.as_deref(), now) {
+        Ok(time) => time,
+        Err(err) => return Err(format.reject(Error::Invalid(err))),
+    };
+    // `burn` alone deletes the paste on its first view
+    let max_views = match data.get("max-views") {
+        Some(views) => match String::from_utf8_lossy(views).parse::<u64>() {
+            Ok(0) => {
+                return Err(
+                    format.reject(Error::Invalid(String::from("max-views must be at least 1")))
                 )
-                .into_response());
             }
+            Ok(views) => Some(views),
+            Err(err) => return Err(format.reject(Error::Invalid(format!("max-views: {}", err)))),
+        },
+        None => data
+            .get("burn")
+            .filter(|v| !matches!(&v[..], b"0" | b"false"))
+            .map(|_| 1),
+    };
+
+    // a request without a body streams an empty one
+    let content = match &data.content {
+        Some(content) if content.size > 0 => content,
+        _ => return Err(format.reject(Error::BadRequest(String::from("no content")))),
+    };
+    let data = match store_content(&db, content, path.as_str() == "/u").await {
+        Ok(data) => data,
+        Err(err) => {
+            return Err(format.reject(Error::Internal(format!("storing upload failed: {}", err))))
         }
-    }
-    let res = add_record(db.clone(), &item);
+    };
+    let mut item: DataBaseItem = match data {
+        // short links are normalized, so their hash is not the one of the upload
+        Some(data @ DataType::ShortLink(_)) => DataBaseItem::new(data, custom_url.clone(), None),
+        Some(data) => DataBaseItem::with_hash(data, content.hash, custom_url.clone(), None),
+        None => {
+            return Err(format.reject(Error::Invalid(String::from("short links must be utf-8"))))
+        }
+    };
+    let mut secret = Some(item.reset_secret());
+    item.owner = caller.map(|user| user.name);
+    item.max_views = max_views;
+    item.destroy_time = destroy_time;
+    let res = {
+        let _blobs = db.blobs().lock();
+        let new_blob = store_blob(&db, content, &item.data).map_err(|e| format.reject(e))?;
+        let res = db.add_record(&mut item);
+        let used = match &res {
+            Ok(()) => true,
+            Err(model::DataBaseErrorType::Existed(t)) => t.hash == item.hash,
+            Err(_) => false,
+        };
+        if new_blob && !used {
+            db.blobs().release(&item.data);
+        }
+        res
+    };
     let upload_status: UploadStatus;
     match res {
         Ok(_) => upload_status = UploadStatus::Created,
         Err(t) => match t {
+            // a custom url can only be reused for the very same content
+            model::DataBaseErrorType::Existed(t)
+                if custom_url.is_some() && (t.custom_url != custom_url || t.hash != item.hash) =>
+            {
+                return Err(format.reject(Error::Conflict(format!(
+                    "custom url {} is taken",
+                    custom_url.unwrap_or_default()
+                ))));
+            }
             model::DataBaseErrorType::Existed(t) => {
                 upload_status = UploadStatus::Existed;
-                item = t;
-            }
-            model::DataBaseErrorType::Failed => upload_status = UploadStatus::Failed,
-            model::DataBaseErrorType::NotFound => {
-                unreachable!()
+                item = *t;
+                // the secret belongs to whoever created the paste
+                secret = None;
             }
+            e => return Err(format.reject(Error::from(e))),
         },
     }
 
     let response = UploadResponse {
-        date: now.to_string(),
+        date: now,
         digest: item.hash,
-        size: content.unwrap().len(),
+        size: content.size as usize,
         status: upload_status,
-        url: format!(
-            "http://{}/{}",
-            url,
-            custom_url.unwrap_or(item.short.clone())
-        ),
+        url: format!("{}/{}", base_url, custom_url.unwrap_or(item.short.clone())),
         short: item.short,
         uuid: item.uuid.to_string(),
+        expiry: item.destroy_time,
+        max_views: item.max_views,
+        secret,
     };
     info!(
         "{} {} of length {}",
-        response.status.to_string(),
-        response.short,
-        response.size
+        response.status, response.short, response.size
     );
-    Ok(warp::reply::with_status(response.to_string(), http::StatusCode::OK).into_response())
+    Ok(format.reply(response.to_string(), &response, http::StatusCode::OK))
 }
 
 pub async fn upload(
     path: FullPath,
-    form: FormData,
-    db: model::DataTrees,
-    url: String,
+    form: UploadForm,
+    db: model::Db,
+    base_url: String,
+    caller: Option<User>,
+    retention: Retention,
+    format: ResponseFormat,
 ) -> Result<warp::reply::Response, Rejection> {
-    process_upload(path, form, db, url, None).await
+    process_upload(path, form, db, base_url, None, caller, retention, format).await
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn custom_url_upload(
     custom_url: String,
     path: FullPath,
-    form: FormData,
-    db: model::DataTrees,
-    url: String,
+    form: UploadForm,
+    db: model::Db,
+    base_url: String,
+    caller: Option<User>,
+    retention: Retention,
+    format: ResponseFormat,
 ) -> Result<warp::reply::Response, Rejection> {
     if custom_url.contains('.') {
-        return Ok(warp::reply::with_status(
-            "custom url should not contain '.' ".to_string(),
-            http::StatusCode::INTERNAL_SERVER_ERROR,
-        )
-        .into_response());
+        return Err(format.reject(Error::Invalid(String::from(
+            "custom url should not contain '.'",
+        ))));
     }
-    process_upload(path, form, db, url, Some(custom_url)).await
+    process_upload(
+        path,
+        form,
+        db,
+        base_url,
+        Some(custom_url),
+        caller,
+        retention,
+        format,
+    )
+    .await
 }
 
 pub async fn view_data(
     key: String,
-    db: model::DataTrees,
+    db: model::Db,
+    format: ResponseFormat,
 ) -> Result<warp::reply::Response, Rejection> {
     let mut database_key: String = key.to_lowercase();
     let mut ext: String = String::from("txt");
@@ -205,159 +500,411 @@ pub async fn view_data(
         ext = String::from(res[res.len() - 1]);
         has_ext = true;
     }
-    if let Ok(data) = model::query_record(db.clone(), database_key) {
-        info!("get {} success", key);
-        if let Some(t) = data.destroy_time {
-            if now > t {
-                info!("... but it's expired");
-                let delete_res = delete_record(db, data.uuid);
-                match delete_res {
-                    Ok(_) => {
-                        log::info!("delete {} success", key);
-                    }
-                    Err(_) => {
-                        log::warn!("delete {} key failed", key);
-                    }
+    let data = match db.query_record(&database_key) {
+        Ok(data) => data,
+        Err(e) => {
+            info!("get {} failed", key);
+            return Err(format.reject(e.into()));
+        }
+    };
+    info!("get {} success", key);
+    if let Some(t) = data.destroy_time {
+        if now > t {
+            info!("... but it's expired");
+            let delete_res = db.delete_record(data.uuid);
+            match delete_res {
+                Ok(_) => {
+                    log::info!("delete {} success", key);
+                }
+                Err(_) => {
+                    log::warn!("delete {} key failed", key);
                 }
-                return Ok(warp::reply::with_status(
-                    String::from("expired"),
-                    http::StatusCode::BAD_REQUEST,
-                )
-                .into_response());
             }
+            return Err(format.reject(Error::Expired(String::from("expired"))));
+        }
+    }
+    // a view only counts once it could be served, so a failed one
+    // neither burns the paste nor uses up one of its views
+    let reply = serve_data(data.data, &db, &ext, has_ext)
+        .await
+        .map_err(|e| format.reject(e))?;
+    let burn = match (db.record_view(data.uuid, now), data.max_views) {
+        (Ok(views), Some(max)) if views > max => {
+            info!("... but it has burnt");
+            return Err(format.reject(not_found()));
+        }
+        (Ok(views), Some(max)) => views == max,
+        // deleted by the view that burnt it
+        (Err(_), Some(_)) => return Err(format.reject(not_found())),
+        (Err(_), None) => {
+            log::warn!("counting a view of {} failed", key);
+            false
+        }
+        (Ok(_), None) => false,
+    };
+    if burn {
+        // an open blob can still be streamed once it has been removed
+        match db.delete_record(data.uuid) {
+            Ok(_) => log::info!("burnt {} after reading", key),
+            Err(_) => log::warn!("burning {} failed", key),
         }
-        match data.data {
-            DataType::Text(c) => {
+    }
+    Ok(reply)
+}
+
+fn not_found() -> Error {
+    Error::NotFound(String::from("not found"))
+}
+
+/// `data` as the reply to a view, highlighted when it has an extension
+async fn serve_data(
+    data: DataType,
+    db: &model::Db,
+    ext: &str,
+    has_ext: bool,
+) -> Result<warp::reply::Response, Error> {
+    match data {
+        DataType::Text(c) => {
+            log::info!("replying code {}", c.chars().take(10).collect::<String>());
+            if has_ext {
                 log::info!(
-                    "replying code {}",
-                    c.chars().into_iter().take(10).collect::<String>()
+                    "highlighting code {}",
+                    c.chars().take(10).collect::<String>()
                 );
-                if has_ext {
-                    log::info!(
-                        "highlighting code {}",
-                        c.chars().into_iter().take(10).collect::<String>()
-                    );
-                    let html = highlight_lines(&c, &ext);
-                    if let Some(html) = html {
-                        return Ok(warp::reply::html(html).into_response());
-                    }
-                    log::warn!(
-                        "highlight code {} with ext {} failed",
-                        c.chars().into_iter().take(10).collect::<String>(),
-                        ext
-                    )
+                let html = highlight_lines(&c, ext);
+                if let Some(html) = html {
+                    return Ok(warp::reply::html(html).into_response());
                 }
-                return Ok(warp::reply::with_status(c, http::StatusCode::OK).into_response());
+                log::warn!(
+                    "highlight code {} with ext {} failed",
+                    c.chars().take(10).collect::<String>(),
+                    ext
+                )
             }
-            DataType::ShortLink(l) => {
-                log::info!("replying short link {}", l);
-                let res = l.parse::<Uri>();
-                match res {
-                    Ok(t) => {
-                        return Ok(warp::redirect(t).into_response());
-                    }
-                    Err(e) => {
-                        return Ok(warp::reply::with_status(
-                            e.to_string(),
-                            http::StatusCode::BAD_REQUEST,
+            Ok(warp::reply::with_status(c, http::StatusCode::OK).into_response())
+        }
+        DataType::ShortLink(l) => {
+            log::info!("replying short link {}", l);
+            let res = l.parse::<Uri>();
+            match res {
+                Ok(t) => Ok(warp::redirect(t).into_response()),
+                Err(e) => Err(Error::Invalid(e.to_string())),
+            }
+        }
+        DataType::Binary(t) => {
+            //TODO: guess mime
+            log::info!("serving binary");
+            if has_ext {
+                log::info!("guessing mime type");
+                let guess = mime_guess::from_ext(ext);
+                let mime = guess.first();
+                return match mime {
+                    Some(mime) => {
+                        log::info!("guess {} as {}", ext, mime);
+                        Ok(
+                            warp::reply::with_header(t, "content-type", mime.to_string())
+                                .into_response(),
                         )
-                        .into_response())
                     }
-                }
+                    None => Ok(warp::reply::with_status(t, http::StatusCode::OK).into_response()),
+                };
             }
-            DataType::Binary(t) => {
-                //TODO: guess mime
-                log::info!("serving binary");
-                if has_ext {
-                    log::info!("guessing mime type");
-                    let guess = mime_guess::from_ext(ext.as_str());
-                    let mime = guess.first();
-                    return match mime {
-                        Some(mime) => {
-                            log::info!("guess {} as {}", ext, mime.to_string());
-                            Ok(
-                                warp::reply::with_header(t, "content-type", mime.to_string())
-                                    .into_response(),
-                            )
-                        }
-                        None => {
-                            Ok(warp::reply::with_status(t, http::StatusCode::OK).into_response())
-                        }
-                    };
+            Ok(warp::reply::with_status(t, http::StatusCode::OK).into_response())
+        }
+        DataType::Blob { digest, text, .. } => {
+            log::info!("serving blob {}", digest);
+            let file = match db.blobs().open(&digest).await {
+                Ok(file) => file,
+                Err(e) => {
+                    return Err(Error::Internal(format!(
+                        "opening blob {} failed: {}",
+                        digest, e
+                    )))
                 }
-                return Ok(warp::reply::with_status(t, http::StatusCode::OK).into_response());
+            };
+            if text && has_ext {
+                log::info!("highlighting blob {}", digest);
+                if let Ok(c) = tokio::fs::read_to_string(db.blobs().path(&digest)).await {
+                    if let Some(html) = highlight_lines(&c, ext) {
+                        return Ok(warp::reply::html(html).into_response());
+                    }
+                }
+                log::warn!("highlight blob {} with ext {} failed", digest, ext)
             }
+            let content_type = if text {
+                String::from("text/plain; charset=utf-8")
+            } else if let Some(mime) = mime_guess::from_ext(ext).first().filter(|_| has_ext) {
+                log::info!("guess {} as {}", ext, mime);
+                mime.to_string()
+            } else {
+                String::from("application/octet-stream")
+            };
+            let body = Body::wrap_stream(ReaderStream::new(file));
+            Ok(warp::reply::with_header(
+                warp::reply::Response::new(body),
+                "content-type",
+                content_type,
+            )
+            .into_response())
         }
-    } else {
-        info!("get {} failed", key);
-        return Ok(warp::reply::with_status(
-            String::from("not found"),
-            http::StatusCode::NOT_FOUND,
-        )
-        .into_response());
     }
 }
 
 pub async fn delete_data(
     key: String,
-    db: model::DataTrees,
+    db: model::Db,
+    secret: Option<String>,
+    caller: Option<User>,
+    format: ResponseFormat,
 ) -> Result<warp::reply::Response, Rejection> {
-    if let Ok(id) = uuid::Uuid::parse_str(key.as_str()) {
-        let delete_res = delete_record(db, id);
-        match delete_res {
-            Ok(_) => {
-                log::info!("delete {} success", key);
-                return Ok(warp::reply::with_status(
-                    format!("deleted {}", key),
-                    http::StatusCode::OK,
+    let id = authorize(&db, &key, secret, caller)
+        .map_err(|e| format.reject(e))?
+        .uuid;
+    db.delete_record(id).map_err(|e| format.reject(e.into()))?;
+    log::info!("delete {} success", key);
+    let response = DeleteResponse {
+        status: UploadStatus::Deleted,
+        uuid: id.to_string(),
+    };
+    Ok(format.reply(format!("deleted {}", key), &response, http::StatusCode::OK))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn update_data(
+    key: String,
+    db: model::Db,
+    base_url: String,
+    data: UploadForm,
+    secret: Option<String>,
+    caller: Option<User>,
+    retention: Retention,
+    format: ResponseFormat,
+) -> Result<warp::reply::Response, Rejection> {
+    let id = authorize(&db, &key, secret, caller)
+        .map_err(|e| format.reject(e))?
+        .uuid;
+    // a new `sunset` counts from now, without one the expiry is left alone
+    let destroy_time = match data.get("sunset").map(|v| String::from_utf8_lossy(v)) {
+        Some(sunset) => match retention.expiry(Some(&sunset), Utc::now()) {
+            Ok(time) => Some(time),
+            Err(err) => return Err(format.reject(Error::Invalid(err))),
+        },
+        None => None,
+    };
+    // raw body updates always have content, an empty one only changes the expiry
+    let content = data.content.as_ref().filter(|content| content.size > 0);
+    if content.is_none() && destroy_time.is_none() {
+        return Err(format.reject(Error::BadRequest(String::from("nothing to update"))));
+    }
+    if let Some(content) = content {
+        let data = match store_content(&db, content, false).await {
+            Ok(Some(data)) => data,
+            Ok(None) => return Err(format.reject(Error::Invalid(String::from("invalid content")))),
+            Err(err) => {
+                return Err(
+                    format.reject(Error::Internal(format!("storing upload failed: {}", err)))
                 )
-                .into_response());
             }
-            Err(_) => {
-                log::warn!("delete {} key failed", key);
+        };
+        let _blobs = db.blobs().lock();
+        let new_blob = store_blob(&db, content, &data).map_err(|e| format.reject(e))?;
+        let stored = new_blob.then(|| data.clone());
+        match db.replace_data(id, data, content.hash) {
+            Ok(old) => {
+                if let Some(old) = old {
+                    db.blobs().release(&old);
+                }
+            }
+            Err(e) => {
+                if let Some(stored) = stored {
+                    db.blobs().release(&stored);
+                }
+                return Err(format.reject(e.into()));
             }
         }
     }
-    Ok(
-        warp::reply::with_status(format!("{} not found", key), http::StatusCode::NOT_FOUND)
-            .into_response(),
-    )
+    if let Some(time) = destroy_time {
+        db.set_expiry(id, time)
+            .map_err(|e| format.reject(e.into()))?;
+    }
+    let item = db.get_record(id).map_err(|e| format.reject(e.into()))?;
+    log::info!("update {} success", key);
+    let url = format!("{}/{}", base_url, item.short);
+    let response = UploadResponse {
+        date: Utc::now(),
+        size: item.data.size() as usize,
+        digest: item.hash,
+        short: item.short,
+        status: UploadStatus::Updated,
+        url: url.clone(),
+        uuid: item.uuid.to_string(),
+        expiry: item.destroy_time,
+        max_views: item.max_views,
+        secret: None,
+    };
+    Ok(format.reply(format!("{} updated", url), &response, http::StatusCode::OK))
 }
 
-pub async fn update_data(
+pub async fn create_user(
+    name: String,
+    caller: Option<User>,
+    db: model::Db,
+    format: ResponseFormat,
+) -> Result<warp::reply::Response, Rejection> {
+    if !caller.map(|user| user.admin).unwrap_or(false) {
+        return Err(format.reject(Error::Forbidden(String::from(
+            "only admins can create users",
+        ))));
+    }
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(format.reject(Error::Invalid(String::from(
+            "user names may only contain letters, digits, '-' and '_'",
+        ))));
+    }
+    if name == auth::ADMIN {
+        return Err(format.reject(Error::Conflict(format!("user {} exists", name))));
+    }
+    let (user, key) = User::new(name, false);
+    match db.add_user(&user) {
+        Ok(true) => {
+            log::info!("created user {}", user.name);
+            let response = UserResponse {
+                status: UploadStatus::Created,
+                name: user.name,
+                key,
+            };
+            Ok(format.reply(response.to_string(), &response, http::StatusCode::OK))
+        }
+        Ok(false) => Err(format.reject(Error::Conflict(format!("user {} exists", user.name)))),
+        Err(e) => Err(format.reject(e.into())),
+    }
+}
+
+pub async fn backup(
+    caller: Option<User>,
+    snapshots: Arc<Snapshots>,
+    format: ResponseFormat,
+) -> Result<warp::reply::Response, Rejection> {
+    match caller {
+        Some(user) if user.admin => {}
+        Some(_) => {
+            return Err(format.reject(Error::Forbidden(String::from(
+                "only admins can take backups",
+            ))))
+        }
+        None => {
+            return Err(format.reject(Error::Unauthorized(String::from(
+                "an api key is needed to take backups",
+            ))))
+        }
+    }
+    let taken = tokio::task::spawn_blocking(move || snapshots.take())
+        .await
+        .map_err(|e| format.reject(Error::Internal(format!("snapshot task failed: {}", e))))?;
+    let snapshot = match taken {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            return Err(format.reject(Error::Conflict(String::from(
+                "a snapshot is being taken already",
+            ))))
+        }
+        Err(e) => return Err(format.reject(e.into())),
+    };
+    log::info!("took snapshot {}", snapshot.path.display());
+    let response = BackupResponse {
+        status: UploadStatus::Created,
+        snapshot: snapshot
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        users: snapshot.summary.users,
+        pastes: snapshot.summary.pastes,
+        skipped: snapshot.summary.skipped,
+    };
+    Ok(format.reply(response.to_string(), &response, http::StatusCode::OK))
+}
+
+pub async fn paste_metadata(
     key: String,
-    db: model::DataTrees,
-    host: String,
-    form: FormData,
+    db: model::Db,
+    format: ResponseFormat,
 ) -> Result<warp::reply::Response, Rejection> {
-    let parts: Vec<Part> = form.try_collect().await.map_err(|e| {
-        eprintln!("form error: {}", e);
-        warp::reject::reject()
-    })?;
-    let data = read_multipart_form(parts).await;
-    let content = data.get("c").or_else(|| data.get("content"));
-    if let Ok(id) = uuid::Uuid::parse_str(key.as_str()) {
-        if let Some(content) = content {
-            let data = DataType::from_bytes(content.clone(), None).unwrap();
-            let update_res = model::update_record(db.clone(), id, data);
-            let item = model::query_record(db, key.clone()).unwrap();
-            match update_res {
-                Ok(_) => {
-                    log::info!("update {} success", key);
-                    return Ok(warp::reply::with_status(
-                        format!("http://{}/{} updated", host, item.short),
-                        http::StatusCode::OK,
-                    )
-                    .into_response());
-                }
-                Err(_) => {
-                    log::warn!("update {} failed", key);
-                }
-            }
+    let item = match db.query_record(&key.to_lowercase()) {
+        Ok(item) if item.destroy_time.is_none_or(|t| t > Utc::now()) => item,
+        Ok(_) => return Err(format.reject(Error::Expired(String::from("expired")))),
+        Err(e) => return Err(format.reject(e.into())),
+    };
+    let metadata = PasteMetadata::from(item);
+    Ok(format.reply(metadata.details(), &metadata, http::StatusCode::OK))
+}
+
+pub async fn list_pastes(
+    caller: Option<User>,
+    query: HashMap<String, String>,
+    db: model::Db,
+    page_size: PageSize,
+    format: ResponseFormat,
+) -> Result<warp::reply::Response, Rejection> {
+    let user = match caller {
+        Some(user) => user,
+        None => {
+            return Err(format.reject(Error::Unauthorized(String::from(
+                "an api key is needed to list pastes",
+            ))))
         }
+    };
+    let limit = match query.get("limit").map(|l| l.parse::<usize>()) {
+        Some(Ok(limit)) => limit.clamp(1, page_size.max),
+        Some(Err(e)) => return Err(format.reject(Error::BadRequest(format!("limit: {}", e)))),
+        None => page_size.default,
+    };
+    let after = match query.get("cursor").map(|c| decode_cursor(c)) {
+        Some(Some(cursor)) => Some(cursor),
+        Some(None) => return Err(format.reject(Error::BadRequest(String::from("invalid cursor")))),
+        None => None,
+    };
+    let items = match db.list_records(&user.name, after, limit) {
+        Ok(items) => items,
+        Err(_) => {
+            return Err(format.reject(Error::Internal(format!(
+                "listing pastes of {} failed",
+                user.name
+            ))))
+        }
+    };
+    let pastes: Vec<PasteMetadata> = items.into_iter().map(PasteMetadata::from).collect();
+    let next_cursor = match pastes.last() {
+        Some(last) if pastes.len() == limit => Some(encode_cursor(last)),
+        _ => None,
+    };
+    let response = ListResponse {
+        pastes,
+        next_cursor,
+    };
+    Ok(format.reply(response.to_string(), &response, http::StatusCode::OK))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::BlobStore;
+    use crate::memory::MemoryStore;
+
+    #[tokio::test]
+    async fn pastes_burn_on_their_last_view() {
+        let dir = std::env::temp_dir().join(format!("rspb-controller-{}", uuid::Uuid::new_v4()));
+        let db: model::Db = Arc::new(MemoryStore::new(BlobStore::new(dir.clone(), 16).unwrap()));
+        let mut item = DataBaseItem::new(DataType::Text(String::from("read me")), None, None);
+        item.max_views = Some(3);
+        db.add_record(&mut item).unwrap();
+        let view = || view_data(item.short.clone(), db.clone(), ResponseFormat::Text);
+        for _ in 0..3 {
+            assert!(view().await.is_ok());
+        }
+        assert!(db.get_record(item.uuid).is_err());
+        assert!(view().await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
     }
-    Ok(
-        warp::reply::with_status(format!("{} not found", key), http::StatusCode::BAD_REQUEST)
-            .into_response(),
-    )
 }
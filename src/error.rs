@@ -0,0 +1,154 @@
+use std::fmt;
+
+use serde::Serialize;
+use warp::{http::StatusCode, reject, Rejection, Reply};
+
+use crate::controller::ResponseFormat;
+use crate::model::DataBaseErrorType;
+
+/// everything a request can fail with, turned into a response by
+/// `handle_rejection`
+#[derive(Debug)]
+pub enum Error {
+    /// the request itself is malformed
+    BadRequest(String),
+    /// no or an unknown api key
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    MethodNotAllowed(String),
+    /// the paste existed but has expired
+    Expired(String),
+    Conflict(String),
+    TooLarge(String),
+    /// well formed, but asking for something that can't be done
+    Invalid(String),
+    /// logged, the client only learns that something went wrong
+    Internal(String),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Error::Expired(_) => StatusCode::GONE,
+            Error::Conflict(_) => StatusCode::CONFLICT,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Internal(_) => write!(f, "internal error"),
+            Error::BadRequest(m)
+            | Error::Unauthorized(m)
+            | Error::Forbidden(m)
+            | Error::NotFound(m)
+            | Error::MethodNotAllowed(m)
+            | Error::Expired(m)
+            | Error::Conflict(m)
+            | Error::TooLarge(m)
+            | Error::Invalid(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl warp::reject::Reject for Error {}
+
+impl Error {
+    /// the rejections warp makes itself, which can't know the format asked
+    /// for
+    fn from_rejection(err: &Rejection) -> Option<Self> {
+        if err.is_not_found() {
+            Some(Error::NotFound(String::from("not found")))
+        } else if err.find::<reject::PayloadTooLarge>().is_some() {
+            Some(Error::TooLarge(String::from("upload is too large")))
+        } else if err.find::<reject::LengthRequired>().is_some() {
+            Some(Error::BadRequest(String::from(
+                "a content length is required",
+            )))
+        } else if let Some(e) = err.find::<reject::InvalidQuery>() {
+            Some(Error::BadRequest(e.to_string()))
+        } else if let Some(e) = err.find::<reject::InvalidHeader>() {
+            Some(Error::BadRequest(e.to_string()))
+        } else if let Some(e) = err.find::<reject::MissingHeader>() {
+            Some(Error::BadRequest(e.to_string()))
+        } else if err.find::<reject::UnsupportedMediaType>().is_some() {
+            Some(Error::BadRequest(String::from("unsupported content type")))
+        } else if err.find::<reject::MethodNotAllowed>().is_some() {
+            Some(Error::MethodNotAllowed(String::from("method not allowed")))
+        } else {
+            None
+        }
+    }
+}
+
+impl From<DataBaseErrorType> for Error {
+    fn from(e: DataBaseErrorType) -> Self {
+        match e {
+            DataBaseErrorType::NotFound => Error::NotFound(e.to_string()),
+            DataBaseErrorType::Existed(_) => Error::Conflict(e.to_string()),
+            DataBaseErrorType::Failed
+            | DataBaseErrorType::Io(_)
+            | DataBaseErrorType::Corrupt(_)
+            | DataBaseErrorType::Serialization(_) => Error::Internal(e.to_string()),
+        }
+    }
+}
+
+/// an `Error` to be reported in the format the client asked for, plain text
+/// is used for a bare `Error`
+#[derive(Debug)]
+pub struct Formatted(pub Error, pub ResponseFormat);
+
+impl warp::reject::Reject for Formatted {}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    status: &'static str,
+    error: String,
+}
+
+fn reply(error: &Error, format: ResponseFormat) -> warp::reply::Response {
+    if let Error::Internal(details) = error {
+        log::warn!("{}", details);
+    }
+    let status = error.status();
+    let mut response = match format {
+        ResponseFormat::Text => warp::reply::with_status(error.to_string(), status).into_response(),
+        ResponseFormat::Json => {
+            let json = ErrorResponse {
+                status: "failed",
+                error: error.to_string(),
+            };
+            warp::reply::with_status(warp::reply::json(&json), status).into_response()
+        }
+    };
+    if let Error::Unauthorized(_) = error {
+        response
+            .headers_mut()
+            .insert("www-authenticate", "Bearer".parse().unwrap());
+    }
+    response
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if let Some(Formatted(error, format)) = err.find() {
+        return Ok(reply(error, *format));
+    }
+    if let Some(error) = err.find::<Error>() {
+        return Ok(reply(error, ResponseFormat::Text));
+    }
+    match Error::from_rejection(&err) {
+        Some(error) => Ok(reply(&error, ResponseFormat::Text)),
+        None => Err(err),
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::{HashMap, HashSet};
+use std::{fs, io, path::Path};
+
+use uuid::Uuid;
+
+use crate::model::{
+    self, decode, encode, DataBaseErrorType, DataTrees, DataType, Record, SharedContent,
+};
+
+/// how many problems a check found, and how many of them it repaired
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub found: usize,
+    pub repaired: usize,
+}
+
+/// walk every tree of a sled database, reporting entries that don't agree
+/// with each other and repairing them when `repair` is set
+///
+/// rspb must not be running, as the trees are checked one after the other
+pub fn run(trees: &DataTrees, repair: bool) -> Result<Summary, DataBaseErrorType> {
+    let mut check = Check {
+        trees,
+        repair,
+        summary: Summary::default(),
+    };
+    let mut contents = check.contents()?;
+    let records = check.records(&mut contents)?;
+    check.references(&records, &mut contents)?;
+    check.index(&trees.short_to_uuid_db, "short id", &records, |r| {
+        Some(r.short.as_bytes().to_vec())
+    })?;
+    check.index(&trees.custom_to_uuid_db, "custom url", &records, |r| {
+        r.custom_url.as_ref().map(|url| url.as_bytes().to_vec())
+    })?;
+    check.index(&trees.expiry_to_uuid_db, "expiry entry", &records, |r| {
+        r.destroy_time.map(|t| model::expiry_key(t, &r.uuid))
+    })?;
+    check.index(&trees.owner_to_uuid_db, "owner entry", &records, |r| {
+        r.owner
+            .as_ref()
+            .map(|owner| model::owner_key(owner, r.created_at, &r.uuid))
+    })?;
+    check.stats(&records)?;
+    check.blobs(&contents)?;
+    if repair {
+        trees.db.flush()?;
+    }
+    Ok(check.summary)
+}
+
+struct Check<'a> {
+    trees: &'a DataTrees,
+    repair: bool,
+    summary: Summary,
+}
+
+impl Check<'_> {
+    /// print a problem, returning whether to repair it
+    fn found(&mut self, problem: String, repairable: bool) -> bool {
+        self.summary.found += 1;
+        let repair = self.repair && repairable;
+        if repair {
+            self.summary.repaired += 1;
+        }
+        let note = match (repairable, repair) {
+            (false, _) => ", which can't be repaired",
+            (true, true) => ", repairing",
+            (true, false) => "",
+        };
+        println!("{}{}", problem, note);
+        repair
+    }
+
+    /// every readable content along with its blob, if it has one
+    fn contents(&mut self) -> Result<HashMap<String, SharedContent>, DataBaseErrorType> {
+        let mut contents = HashMap::new();
+        for entry in self.trees.content_db.iter() {
+            let (key, value) = entry?;
+            let name = show_key(&key);
+            let content = match decode::<SharedContent>(&value) {
+                Some(content) => content,
+                None => {
+                    if self.found(format!("content {} can't be read", name), true) {
+                        self.trees.content_db.remove(&key)?;
+                    }
+                    continue;
+                }
+            };
+            if let DataType::Blob { digest, .. } = &content.data {
+                match hash_file(&self.trees.blobs.path(digest)) {
+                    Ok(hash) if hash == *digest => {}
+                    Ok(_) => {
+                        self.found(
+                            format!("blob {} of content {} is corrupt", digest, name),
+                            false,
+                        );
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        let problem = format!("blob {} of content {} is missing", digest, name);
+                        if self.found(problem, true) {
+                            self.trees.content_db.remove(&key)?;
+                        }
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            contents.insert(name, content);
+        }
+        Ok(contents)
+    }
+
+    /// every readable record whose content is there, with the hash of its
+    /// content
+    fn records(
+        &mut self,
+        contents: &mut HashMap<String, SharedContent>,
+    ) -> Result<HashMap<Uuid, Record>, DataBaseErrorType> {
+        let mut records = HashMap::new();
+        for entry in self.trees.db.iter() {
+            let (key, value) = entry?;
+            let record = decode::<Record>(&value).filter(|r| r.uuid.as_bytes() == &key[..]);
+            let mut record = match record {
+                Some(record) => record,
+                None => {
+                    if self.found(format!("paste {} can't be read", show_uuid(&key)), true) {
+                        self.trees.db.remove(&key)?;
+                    }
+                    continue;
+                }
+            };
+            let data = match contents.get(&record.content) {
+                Some(content) => content.data.clone(),
+                None => {
+                    if self.found(format!("paste {} has no content", record.uuid), true) {
+                        self.trees.db.remove(&key)?;
+                    }
+                    continue;
+                }
+            };
+            let hash = match &data {
+                DataType::Blob { digest, .. } => digest.clone(),
+                data => blake3::hash(data.get_data()).to_hex().to_string(),
+            };
+            if hash != record.hash {
+                let problem = format!(
+                    "paste {} has hash {} but its content hashes to {}",
+                    record.uuid, record.hash, hash
+                );
+                if self.found(problem, true) {
+                    self.rehash(&mut record, hash, data, contents)?;
+                }
+            }
+            records.insert(record.uuid, record);
+        }
+        Ok(records)
+    }
+
+    /// move `record` over to the content its actual `hash` is shared under,
+    /// leaving its previous content to `references`
+    fn rehash(
+        &mut self,
+        record: &mut Record,
+        hash: String,
+        data: DataType,
+        contents: &mut HashMap<String, SharedContent>,
+    ) -> Result<(), DataBaseErrorType> {
+        let key = model::content_key(&hash, &data);
+        let content = contents
+            .entry(key.clone())
+            .and_modify(|content| content.refs += 1)
+            .or_insert_with(|| SharedContent::new(data));
+        self.trees
+            .content_db
+            .insert(key.as_bytes(), encode(content)?)?;
+        if let Some(old) = contents.get_mut(&record.content) {
+            old.refs = old.refs.saturating_sub(1);
+            self.trees
+                .content_db
+                .insert(record.content.as_bytes(), encode(old)?)?;
+        }
+        record.hash = hash;
+        record.content = key;
+        self.trees
+            .db
+            .insert(record.uuid.as_bytes(), encode(record)?)?;
+        Ok(())
+    }
+
+    /// every content must be counted once for each paste using it, and
+    /// dropped once none does
+    fn references(
+        &mut self,
+        records: &HashMap<Uuid, Record>,
+        contents: &mut HashMap<String, SharedContent>,
+    ) -> Result<(), DataBaseErrorType> {
+        let mut used: HashMap<&str, u64> = HashMap::new();
+        for record in records.values() {
+            *used.entry(&record.content).or_default() += 1;
+        }
+        let mut unused = Vec::new();
+        for (key, content) in contents.iter_mut() {
+            let refs = used.get(key.as_str()).copied().unwrap_or(0);
+            if refs == 0 {
+                if self.found(format!("content {} is used by no paste", key), true) {
+                    self.trees.content_db.remove(key.as_bytes())?;
+                    unused.push(key.clone());
+                }
+            } else if refs != content.refs {
+                let problem = format!(
+                    "content {} counts {} references but is used by {} pastes",
+                    key, content.refs, refs
+                );
+                if self.found(problem, true) {
+                    content.refs = refs;
+                    self.trees
+                        .content_db
+                        .insert(key.as_bytes(), encode(&*content)?)?;
+                }
+            }
+        }
+        for key in unused {
+            // its blob is left to `blobs`
+            contents.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// the entries of `tree` must be exactly the keys `key_of` gives for the
+    /// records, each pointing at its record
+    fn index(
+        &mut self,
+        tree: &sled::Tree,
+        name: &str,
+        records: &HashMap<Uuid, Record>,
+        key_of: impl Fn(&Record) -> Option<Vec<u8>>,
+    ) -> Result<(), DataBaseErrorType> {
+        let mut expected: HashMap<Vec<u8>, Uuid> = HashMap::new();
+        for record in records.values() {
+            if let Some(key) = key_of(record) {
+                if let Some(other) = expected.insert(key.clone(), record.uuid) {
+                    let problem = format!(
+                        "pastes {} and {} share the {} {}",
+                        other,
+                        record.uuid,
+                        name,
+                        show_key(&key)
+                    );
+                    self.found(problem, false);
+                }
+            }
+        }
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let points_at_owner = Uuid::from_slice(&value)
+                .ok()
+                .and_then(|uuid| records.get(&uuid))
+                .and_then(&key_of)
+                .is_some_and(|k| k[..] == key[..]);
+            if points_at_owner {
+                continue;
+            }
+            let problem = format!("{} {} points at no paste having it", name, show_key(&key));
+            if self.found(problem, true) {
+                tree.remove(&key)?;
+            }
+        }
+        for (key, uuid) in expected {
+            if tree.contains_key(&key)? {
+                continue;
+            }
+            let problem = format!("paste {} is missing its {} {}", uuid, name, show_key(&key));
+            if self.found(problem, true) {
+                tree.insert(key, uuid.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// view statistics are dropped along with their paste
+    fn stats(&mut self, records: &HashMap<Uuid, Record>) -> Result<(), DataBaseErrorType> {
+        for key in self.trees.stats_db.iter().keys() {
+            let key = key?;
+            let known = Uuid::from_slice(&key).is_ok_and(|uuid| records.contains_key(&uuid));
+            if known {
+                continue;
+            }
+            let problem = format!("view statistics of {} belong to no paste", show_uuid(&key));
+            if self.found(problem, true) {
+                self.trees.stats_db.remove(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// files in the blob store no content refers to, like leftovers of an
+    /// interrupted upload
+    fn blobs(
+        &mut self,
+        contents: &HashMap<String, SharedContent>,
+    ) -> Result<(), DataBaseErrorType> {
+        let used: HashSet<&str> = contents
+            .values()
+            .filter_map(|content| content.data.blob_digest())
+            .collect();
+        let dirs = match fs::read_dir(&self.trees.blobs.dir) {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for dir in dirs {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let path = file?.path();
+                let digest = path.file_name().map(|name| name.to_string_lossy());
+                if digest.is_some_and(|digest| used.contains(&*digest)) {
+                    continue;
+                }
+                let problem = format!("blob {} is used by no content", path.display());
+                if self.found(problem, true) {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// a key as text when it is printable, as hex otherwise
+fn show_key(key: &[u8]) -> String {
+    match std::str::from_utf8(key) {
+        Ok(s) if s.chars().all(|c| c.is_ascii_graphic()) => s.to_string(),
+        _ => key.iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
+
+fn show_uuid(key: &[u8]) -> String {
+    Uuid::from_slice(key).map_or_else(|_| show_key(key), |uuid| uuid.to_string())
+}
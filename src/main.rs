@@ -1,39 +1,294 @@
-use config::Config;
-use model::DataTrees;
+use std::{collections::HashMap, net::SocketAddr, path::Path, sync::Arc};
+
+use clap::Parser;
+use cli::Command;
+use config::{Backend, Config};
+use memory::MemoryStore;
 use mimalloc::MiMalloc;
+use model::{DataBaseErrorType, DataTrees};
+use sqlite::SqliteStore;
 
 use warp::Filter;
+mod archive;
+mod auth;
+mod backup;
 mod base32;
+mod blob;
+mod cli;
 mod config;
 mod controller;
+mod error;
+mod fsck;
 mod highlighter;
 mod markdown;
+mod memory;
+mod migration;
 mod model;
+mod sqlite;
+mod sweeper;
+mod ttl;
+mod upload;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+fn open_blobs(config: &Config) -> Result<blob::BlobStore, DataBaseErrorType> {
+    Ok(blob::BlobStore::new(
+        config.blob_dir.clone(),
+        config.blob_threshold,
+    )?)
+}
+
+/// the sled database, which fails while another rspb holds it
+fn open_sled(config: &Config) -> Result<DataTrees, DataBaseErrorType> {
+    let sled_config = sled::Config::default()
+        .cache_capacity(config.db_cache_capacity)
+        .use_compression(true)
+        .path(&config.db_path);
+    let db: sled::Db = sled_config.open()?;
+    DataTrees::new(db, open_blobs(config)?)
+}
+
+fn open_store(config: &Config) -> Result<model::Db, DataBaseErrorType> {
+    Ok(match config.backend {
+        Backend::Sled => Arc::new(open_sled(config)?),
+        Backend::Sqlite => Arc::new(SqliteStore::open(&config.sqlite_path, open_blobs(config)?)?),
+        Backend::Memory => Arc::new(MemoryStore::new(open_blobs(config)?)),
+    })
+}
+
+/// `rspb fsck [--repair]`, returning the exit code: 1 when problems are left
+fn fsck(config: &Config, repair: bool) -> i32 {
+    if config.backend != Backend::Sled {
+        eprintln!("fsck only checks sled databases");
+        return 2;
+    }
+    match open_sled(config).and_then(|db| fsck::run(&db, repair)) {
+        Ok(summary) => {
+            println!(
+                "{} problems found, {} repaired",
+                summary.found, summary.repaired
+            );
+            (summary.found > summary.repaired) as i32
+        }
+        Err(e) => {
+            eprintln!("fsck failed: {}", e);
+            2
+        }
+    }
+}
+
+/// `rspb export <file>` or `rspb import <file>`, returning the exit code
+fn archive(config: &Config, command: &str, path: &Path) -> i32 {
+    if config.backend == Backend::Memory {
+        eprintln!("the memory backend keeps no pastes to {}", command);
+        return 2;
+    }
+    let res = open_store(config).and_then(|db| match command {
+        "export" => archive::export(db.as_ref(), path),
+        _ => archive::import(db.as_ref(), path),
+    });
+    match res {
+        Ok(summary) => {
+            println!(
+                "{}ed {} users and {} pastes, skipped {} pastes",
+                command, summary.users, summary.pastes, summary.skipped
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("{} failed: {}", command, e);
+            1
+        }
+    }
+}
+
+/// `rspb gc`, purging expired pastes once
+fn gc(config: &Config) -> i32 {
+    match open_store(config).and_then(sweeper::sweep) {
+        Ok(purged) => {
+            println!("purged {} expired pastes", purged);
+            0
+        }
+        Err(e) => {
+            eprintln!("gc failed: {}", e);
+            1
+        }
+    }
+}
+
+/// `rspb stats`, returning the exit code
+fn stats(config: &Config) -> i32 {
+    let db = match open_store(config) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("stats failed: {}", e);
+            return 1;
+        }
+    };
+    let (uuids, users) = match (db.uuids(), db.users()) {
+        (Ok(uuids), Ok(users)) => (uuids, users),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("stats failed: {}", e);
+            return 1;
+        }
+    };
+    let now = chrono::Utc::now();
+    let (mut size, mut expired, mut unreadable) = (0, 0, 0);
+    for uuid in &uuids {
+        match db.get_record(*uuid) {
+            Ok(item) => {
+                size += item.data.size();
+                expired += item.destroy_time.is_some_and(|t| t <= now) as usize;
+            }
+            Err(_) => unreadable += 1,
+        }
+    }
+    println!("pastes: {}", uuids.len());
+    println!("expired: {}", expired);
+    println!("unreadable: {}", unreadable);
+    println!("bytes: {}", size);
+    println!("users: {}", users.len());
+    0
+}
+
 #[tokio::main]
 async fn main() {
-    let config: Config = config::Config::load(None).await.unwrap_or_default();
-    let help = markdown::render(
-        tokio::fs::read_to_string("README.md")
-            .await
-            .unwrap_or_else(|_| String::from("cmd | curl -F c=@- https://pb.mgt.moe/")),
-    );
-    highlighter::highlight_lines(&String::from(""), &String::from("rs"));
-    flexi_logger::Logger::try_with_env_or_str("info")
+    let cli = cli::Cli::parse();
+    let (mut config, ignored) = match Config::load(cli.config.as_deref()).await {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("invalid config: {}", e);
+            std::process::exit(2);
+        }
+    };
+    cli.apply(&mut config);
+    if let Err(e) = config.validate() {
+        eprintln!("invalid config: {}", e);
+        std::process::exit(2);
+    }
+    flexi_logger::Logger::try_with_env_or_str(&config.log_level)
         .unwrap()
         .format(flexi_logger::colored_default_format)
         .start()
         .unwrap();
-    let sled_config = sled::Config::default()
-        .cache_capacity(config.db_cache_capacity)
-        .use_compression(true)
-        .path("db");
-    let db: sled::Db = sled_config.open().unwrap();
-    let model: model::DataTrees = DataTrees::new(db);
+    for var in ignored {
+        log::warn!("{} is no setting, it is ignored", var);
+    }
+    let code = match &cli.command {
+        None | Some(Command::Serve) => return serve(config).await,
+        Some(Command::Fsck { repair }) => fsck(&config, *repair),
+        Some(Command::Export { file }) => archive(&config, "export", file),
+        Some(Command::Import { file }) => archive(&config, "import", file),
+        Some(Command::Gc) => gc(&config),
+        Some(Command::Stats) => stats(&config),
+    };
+    std::process::exit(code);
+}
+
+async fn serve(config: Config) {
+    let help_page = config.help_page.clone().unwrap_or_default();
+    let help = markdown::render(match tokio::fs::read_to_string(&help_page).await {
+        Ok(help) => help,
+        Err(e) => {
+            log::warn!("help page {} can't be read: {}", help_page.display(), e);
+            String::from("cmd | curl -F c=@- https://pb.mgt.moe/")
+        }
+    });
+    highlighter::highlight_lines("", "rs");
+    let store = match open_store(&config) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("opening the database failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // snapshots pause the writes of the store while they are taken
+    let paused = Arc::new(backup::Pausable::new(store));
+    let model: model::Db = paused.clone();
+    if config.sweep_interval > 0 {
+        tokio::spawn(sweeper::run(model.clone(), config.sweep_interval));
+    }
+    let snapshots = Arc::new(backup::Snapshots::new(
+        paused,
+        config.backup_dir.clone(),
+        config.backup_retain,
+    ));
+    if config.backup_interval > 0 {
+        tokio::spawn(backup::run(snapshots.clone(), config.backup_interval));
+    }
+    let snapshots_filter = warp::any().map(move || snapshots.clone());
     let model_filter = warp::any().map(move || model.clone());
+    let admin_key = config
+        .admin_key
+        .as_deref()
+        .map(|key| blake3::hash(key.as_bytes()));
+    // the user an `Authorization: Bearer` api key belongs to, if any
+    let caller_filter = warp::header::optional::<String>("authorization")
+        .and(model_filter.clone())
+        .and(warp::any().map(move || admin_key))
+        .and_then(auth::caller);
+    let format_filter = warp::header::optional::<String>("accept")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(controller::ResponseFormat::negotiate);
+    // the secret of a paste, given as `X-Secret` or `?secret=`
+    let secret_filter = warp::header::optional::<String>("x-secret")
+        .and(warp::query::<HashMap<String, String>>())
+        .map(
+            |header: Option<String>, mut query: HashMap<String, String>| {
+                header.or_else(|| query.remove("secret"))
+            },
+        );
+    if let Err(e) = std::fs::create_dir_all(&config.spool_dir) {
+        eprintln!("creating {} failed: {}", config.spool_dir.display(), e);
+        std::process::exit(1);
+    }
+    let spool = upload::Spool {
+        dir: config.spool_dir.clone(),
+        max_length: config.max_length,
+    };
+    let spool_filter = warp::any().map(move || spool.clone());
+    // links point at `public_url`, or at the host a request was sent to
+    let public_url = config
+        .public_url
+        .as_ref()
+        .map(|url| url.trim_end_matches('/').to_string());
+    let listen = SocketAddr::new(config.ip, config.port);
+    let base_url_filter =
+        warp::header::optional::<String>("host").map(move |host: Option<String>| {
+            match (&public_url, host) {
+                (Some(url), _) => url.clone(),
+                (None, Some(host)) => format!("http://{}", host),
+                (None, None) => format!("http://{}", listen),
+            }
+        });
+    let page_size = config.page_size();
+    let page_size_filter = warp::any().map(move || page_size);
+    let retention = config.retention();
+    let retention_filter = warp::any().map(move || retention);
+    // multipart forms, or the whole body as content for any other content type,
+    // both limited by the spool so that being too large is reported in the
+    // format asked for
+    let form_filter = warp::multipart::form()
+        .max_length(None)
+        .and(spool_filter.clone())
+        .and(format_filter)
+        .and_then(upload::read_multipart_form)
+        .or(warp::header::optional::<String>("content-type")
+            .and_then(|content_type: Option<String>| async move {
+                match content_type {
+                    Some(t) if t.starts_with("multipart/") => Err(warp::reject::reject()),
+                    _ => Ok(()),
+                }
+            })
+            .untuple_one()
+            .and(warp::body::stream())
+            .and(warp::header::headers_cloned())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(spool_filter)
+            .and(format_filter)
+            .and_then(upload::read_raw_body))
+        .unify();
     let help_route = warp::path::end()
         .and(warp::get())
         .map(move || warp::reply::html(help.clone()));
@@ -42,31 +297,70 @@ async fn main() {
         .unify()
         .and(warp::path::full())
         .and(warp::post())
-        .and(warp::multipart::form().max_length(config.max_length))
+        .and(form_filter.clone())
         .and(model_filter.clone())
-        .and(warp::header::<String>("host"))
+        .and(base_url_filter.clone())
+        .and(caller_filter.clone())
+        .and(retention_filter)
+        .and(format_filter)
         .and_then(controller::upload);
     let custom_url_route = warp::post()
         .and(warp::path!(String))
         .and(warp::path::full())
-        .and(warp::multipart::form().max_length(config.max_length))
+        .and(form_filter.clone())
         .and(model_filter.clone())
-        .and(warp::header::<String>("host"))
+        .and(base_url_filter.clone())
+        .and(caller_filter.clone())
+        .and(retention_filter)
+        .and(format_filter)
         .and_then(controller::custom_url_upload);
     let view_route = warp::get()
         .and(warp::path!(String))
         .and(model_filter.clone())
+        .and(format_filter)
         .and_then(controller::view_data);
     let delete_route = warp::delete()
         .and(warp::path!(String))
         .and(model_filter.clone())
+        .and(secret_filter)
+        .and(caller_filter.clone())
+        .and(format_filter)
         .and_then(controller::delete_data);
     let update_route = warp::put()
         .and(warp::path!(String))
         .and(model_filter.clone())
-        .and(warp::header::<String>("host"))
-        .and(warp::multipart::form().max_length(config.max_length))
+        .and(base_url_filter.clone())
+        .and(form_filter.clone())
+        .and(secret_filter)
+        .and(caller_filter.clone())
+        .and(retention_filter)
+        .and(format_filter)
         .and_then(controller::update_data);
+    let list_route = warp::get()
+        .and(warp::path!("api" / "pastes"))
+        .and(caller_filter.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(model_filter.clone())
+        .and(page_size_filter)
+        .and(format_filter)
+        .and_then(controller::list_pastes);
+    let metadata_route = warp::get()
+        .and(warp::path!("api" / "pastes" / String))
+        .and(model_filter.clone())
+        .and(format_filter)
+        .and_then(controller::paste_metadata);
+    let create_user_route = warp::post()
+        .and(warp::path!("api" / "users" / String))
+        .and(caller_filter.clone())
+        .and(model_filter.clone())
+        .and(format_filter)
+        .and_then(controller::create_user);
+    let backup_route = warp::post()
+        .and(warp::path!("admin" / "backup"))
+        .and(caller_filter)
+        .and(snapshots_filter)
+        .and(format_filter)
+        .and_then(controller::backup);
 
     let route = upload_route
         .or(view_route)
@@ -74,6 +368,11 @@ async fn main() {
         .or(custom_url_route)
         .or(update_route)
         .or(help_route)
+        .or(list_route)
+        .or(metadata_route)
+        .or(create_user_route)
+        .or(backup_route)
+        .recover(error::handle_rejection)
         .with(warp::log("rspb"));
-    warp::serve(route).run((config.ip, config.port)).await;
+    warp::serve(route).run(listen).await;
 }
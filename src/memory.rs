@@ -0,0 +1,302 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::UNIX_EPOCH;
+
+use chrono::prelude::*;
+use uuid::Uuid;
+
+use crate::blob::BlobStore;
+use crate::model::{
+    Cursor, DataBaseErrorType, DataBaseItem, DataType, Record, SharedContent, Store, User,
+    ViewStats,
+};
+
+#[derive(Debug, Default)]
+struct Tables {
+    items: HashMap<Uuid, Record>,
+    contents: HashMap<String, SharedContent>,
+    short_to_uuid: HashMap<String, Uuid>,
+    custom_to_uuid: HashMap<String, Uuid>,
+    expiry: BTreeSet<(DateTime<Utc>, Uuid)>,
+    /// owner, creation time and uuid of every paste with an owner
+    owned: BTreeSet<(String, DateTime<Utc>, Uuid)>,
+    stats: HashMap<Uuid, ViewStats>,
+    users: HashMap<String, User>,
+    api_key_to_user: HashMap<String, String>,
+}
+
+impl Tables {
+    fn lookup(&self, key: &str) -> Option<DataBaseItem> {
+        // short -> custom
+        let uuid = self
+            .short_to_uuid
+            .get(key)
+            .or_else(|| self.custom_to_uuid.get(key))?;
+        self.item(uuid)
+    }
+
+    fn item(&self, uuid: &Uuid) -> Option<DataBaseItem> {
+        let record = self.items.get(uuid)?;
+        let content = self.contents.get(&record.content)?;
+        let stats = self.stats.get(uuid).copied().unwrap_or_default();
+        Some(record.clone().into_item(content.data.clone(), stats))
+    }
+
+    /// insert `data` along with its index entries
+    fn insert(&mut self, data: &DataBaseItem) {
+        self.short_to_uuid.insert(data.short.clone(), data.uuid);
+        if let Some(url) = &data.custom_url {
+            self.custom_to_uuid.insert(url.clone(), data.uuid);
+        }
+        if let Some(t) = data.destroy_time {
+            self.expiry.insert((t, data.uuid));
+        }
+        if let Some(owner) = &data.owner {
+            self.owned
+                .insert((owner.clone(), data.created_at, data.uuid));
+        }
+        if data.stats != ViewStats::default() {
+            self.stats.insert(data.uuid, data.stats);
+        }
+        let record = data.record();
+        self.acquire_content(&record.content, &data.data);
+        self.items.insert(data.uuid, record);
+    }
+
+    fn acquire_content(&mut self, key: &str, data: &DataType) {
+        match self.contents.get_mut(key) {
+            Some(content) => content.refs += 1,
+            None => {
+                self.contents
+                    .insert(String::from(key), SharedContent::new(data.clone()));
+            }
+        }
+    }
+
+    /// the content under `key` when this was its last reference
+    fn release_content(&mut self, key: &str) -> Option<DataType> {
+        let content = self.contents.get_mut(key)?;
+        content.refs = content.refs.saturating_sub(1);
+        if content.refs > 0 {
+            return None;
+        }
+        self.contents.remove(key).map(|c| c.data)
+    }
+}
+
+/// pastes kept in memory only, lost on restart; handy for tests and demos
+#[derive(Debug)]
+pub struct MemoryStore {
+    tables: Mutex<Tables>,
+    blobs: BlobStore,
+}
+
+impl MemoryStore {
+    pub fn new(blobs: BlobStore) -> Self {
+        MemoryStore {
+            tables: Mutex::new(Tables::default()),
+            blobs,
+        }
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        // a panic while holding the lock can't leave the maps half updated
+        self.tables.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Store for MemoryStore {
+    fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    fn add_record(&self, data: &mut DataBaseItem) -> Result<(), DataBaseErrorType> {
+        let mut tables = self.tables();
+        if let Some(existing) = tables.item(&data.uuid) {
+            return Err(DataBaseErrorType::Existed(Box::new(existing)));
+        }
+        if let Some(url) = &data.custom_url {
+            if let Some(existing) = tables.lookup(url) {
+                return Err(DataBaseErrorType::Existed(Box::new(existing)));
+            }
+        }
+        let mut short = None;
+        for candidate in data.short_candidates() {
+            // short ids and custom urls are looked up by the same keys
+            let taken = tables
+                .short_to_uuid
+                .get(&candidate)
+                .or_else(|| tables.custom_to_uuid.get(&candidate));
+            match taken {
+                None => {
+                    short = Some(candidate);
+                    break;
+                }
+                Some(uuid) => {
+                    let existing = tables.item(uuid).ok_or(DataBaseErrorType::Failed)?;
+                    if data.is_duplicate_of(&existing) {
+                        return Err(DataBaseErrorType::Existed(Box::new(existing)));
+                    }
+                }
+            }
+        }
+        data.short = short.ok_or(DataBaseErrorType::Failed)?;
+        tables.insert(data);
+        Ok(())
+    }
+
+    fn restore_record(&self, data: &DataBaseItem) -> Result<(), DataBaseErrorType> {
+        let mut tables = self.tables();
+        let existing = tables
+            .item(&data.uuid)
+            .or_else(|| tables.lookup(&data.short))
+            .or_else(|| data.custom_url.as_ref().and_then(|url| tables.lookup(url)));
+        if let Some(existing) = existing {
+            return Err(DataBaseErrorType::Existed(Box::new(existing)));
+        }
+        tables.insert(data);
+        Ok(())
+    }
+
+    fn query_record(&self, key: &str) -> Result<DataBaseItem, DataBaseErrorType> {
+        self.tables().lookup(key).ok_or(DataBaseErrorType::NotFound)
+    }
+
+    fn get_record(&self, key: Uuid) -> Result<DataBaseItem, DataBaseErrorType> {
+        self.tables().item(&key).ok_or(DataBaseErrorType::NotFound)
+    }
+
+    fn replace_data(
+        &self,
+        key: Uuid,
+        value: DataType,
+        hash: blake3::Hash,
+    ) -> Result<Option<DataType>, DataBaseErrorType> {
+        let mut tables = self.tables();
+        let mut item = tables.item(&key).ok_or(DataBaseErrorType::NotFound)?;
+        let old_content = item.content_key();
+        item.data = value;
+        item.hash = String::from(hash.to_hex().as_str());
+        item.updated_at = Utc::now();
+        let record = item.record();
+        if record.content == old_content {
+            tables.items.insert(key, record);
+            return Ok(None);
+        }
+        tables.acquire_content(&record.content, &item.data);
+        tables.items.insert(key, record);
+        Ok(tables.release_content(&old_content))
+    }
+
+    fn set_expiry(
+        &self,
+        key: Uuid,
+        destroy_time: Option<DateTime<Utc>>,
+    ) -> Result<(), DataBaseErrorType> {
+        let mut tables = self.tables();
+        let record = tables
+            .items
+            .get_mut(&key)
+            .ok_or(DataBaseErrorType::NotFound)?;
+        let old_time = std::mem::replace(&mut record.destroy_time, destroy_time);
+        if let Some(t) = old_time {
+            tables.expiry.remove(&(t, key));
+        }
+        if let Some(t) = destroy_time {
+            tables.expiry.insert((t, key));
+        }
+        Ok(())
+    }
+
+    fn remove_record(&self, key: Uuid) -> Result<Option<DataType>, DataBaseErrorType> {
+        let mut tables = self.tables();
+        let data = tables
+            .items
+            .remove(&key)
+            .ok_or(DataBaseErrorType::NotFound)?;
+        tables.short_to_uuid.remove(&data.short);
+        if let Some(url) = &data.custom_url {
+            tables.custom_to_uuid.remove(url);
+        }
+        if let Some(t) = data.destroy_time {
+            tables.expiry.remove(&(t, key));
+        }
+        if let Some(owner) = data.owner.clone() {
+            tables.owned.remove(&(owner, data.created_at, key));
+        }
+        tables.stats.remove(&key);
+        Ok(tables.release_content(&data.content))
+    }
+
+    fn expired_records(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        Ok(self
+            .tables()
+            .expiry
+            .range(..(now, Uuid::nil()))
+            .map(|(_, uuid)| *uuid)
+            .collect())
+    }
+
+    fn record_view(&self, key: Uuid, at: DateTime<Utc>) -> Result<u64, DataBaseErrorType> {
+        let mut tables = self.tables();
+        if !tables.items.contains_key(&key) {
+            return Err(DataBaseErrorType::NotFound);
+        }
+        let stats = tables.stats.entry(key).or_default();
+        stats.record_view(at);
+        Ok(stats.views)
+    }
+
+    fn list_records(
+        &self,
+        owner: &str,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<DataBaseItem>, DataBaseErrorType> {
+        let tables = self.tables();
+        let epoch = DateTime::<Utc>::from(UNIX_EPOCH);
+        let start = (String::from(owner), epoch, Uuid::nil());
+        let end = match after {
+            Some((created_at, uuid)) => (String::from(owner), created_at, uuid),
+            // sorts right after every entry of this owner
+            None => (format!("{}\0", owner), epoch, Uuid::nil()),
+        };
+        Ok(tables
+            .owned
+            .range(start..end)
+            .rev()
+            .take(limit)
+            .filter_map(|(_, _, uuid)| tables.item(uuid))
+            .collect())
+    }
+
+    fn add_user(&self, user: &User) -> Result<bool, DataBaseErrorType> {
+        let mut tables = self.tables();
+        if tables.users.contains_key(&user.name) {
+            return Ok(false);
+        }
+        tables
+            .api_key_to_user
+            .insert(user.key_hash.clone(), user.name.clone());
+        tables.users.insert(user.name.clone(), user.clone());
+        Ok(true)
+    }
+
+    fn find_user(&self, key_hash: &str) -> Result<User, DataBaseErrorType> {
+        let tables = self.tables();
+        tables
+            .api_key_to_user
+            .get(key_hash)
+            .and_then(|name| tables.users.get(name))
+            .cloned()
+            .ok_or(DataBaseErrorType::NotFound)
+    }
+
+    fn uuids(&self) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        Ok(self.tables().items.keys().copied().collect())
+    }
+
+    fn users(&self) -> Result<Vec<User>, DataBaseErrorType> {
+        Ok(self.tables().users.values().cloned().collect())
+    }
+}
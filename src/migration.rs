@@ -0,0 +1,218 @@
+use std::convert::TryInto;
+
+use chrono::prelude::*;
+use serde::Deserialize;
+use sled::transaction::{ConflictableTransactionError, TransactionalTree};
+use sled::Transactional;
+use uuid::Uuid;
+
+use crate::model::{
+    self, generate_secret, hash_secret, DataBaseErrorType, DataBaseItem, DataTrees, DataType,
+    ViewStats,
+};
+
+/// version of the layout of the sled database:
+/// 0. every paste holds its own content
+/// 1. pastes share their content, every value is kept in an envelope with the
+///    version of its own layout
+///
+/// bump this along with the version of a value whose layout changed, so that
+/// every value gets rewritten with the new layout
+pub const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA_KEY: &[u8] = b"schema_version";
+
+/// bring a database written by an older rspb up to `SCHEMA_VERSION`, refusing
+/// one written by a newer rspb
+pub fn run(trees: &DataTrees) -> Result<(), String> {
+    let version = stored_version(trees)?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "the database is of version {} but this rspb only knows up to {}",
+            version, SCHEMA_VERSION
+        ));
+    }
+    if version < SCHEMA_VERSION {
+        share_contents(trees)?;
+        log::info!(
+            "migrated the database from version {} to {}",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    Ok(())
+}
+
+fn stored_version(trees: &DataTrees) -> Result<u32, String> {
+    if let Some(v) = trees.meta_db.get(SCHEMA_KEY).map_err(|e| e.to_string())? {
+        let bytes: [u8; 4] = v
+            .as_ref()
+            .try_into()
+            .map_err(|_| String::from("malformed schema version"))?;
+        return Ok(u32::from_be_bytes(bytes));
+    }
+    // databases without a version are new or hold pastes of version 0
+    if trees.db.is_empty() {
+        trees
+            .meta_db
+            .insert(SCHEMA_KEY, &SCHEMA_VERSION.to_be_bytes())
+            .map_err(|e| e.to_string())?;
+        return Ok(SCHEMA_VERSION);
+    }
+    Ok(0)
+}
+
+fn uuid_of(key: &[u8]) -> String {
+    Uuid::from_slice(key).map_or_else(|_| format!("{:?}", key), |uuid| uuid.to_string())
+}
+
+/// pastes as stored before their content was shared
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+struct LegacyItem {
+    destroy_time: Option<DateTime<Utc>>,
+    custom_url: Option<String>,
+    uuid: Uuid,
+    hash: String,
+    short: String,
+    data: DataType,
+}
+
+impl From<LegacyItem> for DataBaseItem {
+    fn from(item: LegacyItem) -> Self {
+        let now = Utc::now();
+        DataBaseItem {
+            destroy_time: item.destroy_time,
+            max_views: None,
+            custom_url: item.custom_url,
+            // the uuid used to allow updating and deleting, but anyone can
+            // look it up, so these pastes are left to admins
+            secret_hash: hash_secret(&generate_secret()),
+            uuid: item.uuid,
+            hash: item.hash,
+            short: item.short,
+            owner: None,
+            created_at: now,
+            updated_at: now,
+            stats: ViewStats::default(),
+            data: item.data,
+        }
+    }
+}
+
+/// move the content of pastes stored before it was shared into the content
+/// tree, all at once so that a crash can't leave a mix of both
+fn share_contents(trees: &DataTrees) -> Result<(), String> {
+    let mut items = Vec::new();
+    for entry in trees.db.iter() {
+        let (key, value) = entry.map_err(|e| e.to_string())?;
+        match bincode::deserialize::<LegacyItem>(&value) {
+            Ok(item) => {
+                let item = DataBaseItem::from(item);
+                let record = model::encode(&item.record()).map_err(|e| e.to_string())?;
+                items.push((item, record));
+            }
+            Err(_) => log::warn!("paste {} can't be read, leaving it as is", uuid_of(&key)),
+        }
+    }
+    (&trees.db, &trees.content_db, &trees.meta_db)
+        .transaction(
+            |(db, content_db, meta_db): &(
+                TransactionalTree,
+                TransactionalTree,
+                TransactionalTree,
+            )|
+             -> Result<(), ConflictableTransactionError<DataBaseErrorType>> {
+                for (item, record) in &items {
+                    db.insert(item.uuid.as_bytes(), record.as_slice())?;
+                    model::acquire_content(
+                        content_db,
+                        &item.uuid,
+                        &item.content_key(),
+                        &item.data,
+                    )?;
+                }
+                meta_db.insert(SCHEMA_KEY, &SCHEMA_VERSION.to_be_bytes())?;
+                Ok(())
+            },
+        )
+        .map_err(|e| format!("{:?}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::blob::BlobStore;
+    use crate::controller::{self, ResponseFormat};
+    use crate::model::{SharedContent, Store, TreeNames};
+
+    const SHORT: &str = "abcd";
+    const CONTENT: &str = "hello";
+
+    /// a database of version 0 holding a single paste, as this rspb opens it
+    fn upgrade(uuid: Uuid) -> DataTrees {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let legacy = LegacyItem {
+            destroy_time: None,
+            custom_url: None,
+            uuid,
+            hash: blake3::hash(CONTENT.as_bytes()).to_hex().to_string(),
+            short: String::from(SHORT),
+            data: DataType::Text(String::from(CONTENT)),
+        };
+        db.open_tree(TreeNames::DataTree)
+            .unwrap()
+            .insert(uuid.as_bytes(), bincode::serialize(&legacy).unwrap())
+            .unwrap();
+        db.open_tree(TreeNames::ShortNameTree)
+            .unwrap()
+            .insert(SHORT, uuid.as_bytes())
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("rspb-migration-{}", Uuid::new_v4()));
+        DataTrees::new(db, BlobStore::new(dir, 1_000_000).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn upgrades_pastes_holding_their_content() {
+        let uuid = Uuid::new_v4();
+        let trees = upgrade(uuid);
+        assert_eq!(stored_version(&trees).unwrap(), SCHEMA_VERSION);
+        let item = trees.query_record(SHORT).unwrap();
+        assert_eq!(item.uuid, uuid);
+        assert_eq!(item.data, DataType::Text(String::from(CONTENT)));
+        assert_eq!(item.stats, ViewStats::default());
+        let content = trees.content_db.get(item.content_key()).unwrap().unwrap();
+        assert_eq!(model::decode::<SharedContent>(&content).unwrap().refs, 1);
+        std::fs::remove_dir_all(&trees.blobs.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn migrated_pastes_cannot_be_deleted_with_their_uuid() {
+        let uuid = Uuid::new_v4();
+        let trees = upgrade(uuid);
+        let dir = trees.blobs.dir.clone();
+        let db: model::Db = std::sync::Arc::new(trees);
+        let json =
+            || ResponseFormat::negotiate(Some(String::from("application/json")), HashMap::new());
+        let metadata = controller::paste_metadata(String::from(SHORT), db.clone(), json())
+            .await
+            .unwrap();
+        let body = warp::hyper::body::to_bytes(metadata.into_body())
+            .await
+            .unwrap();
+        let shown = serde_json::from_slice::<serde_json::Value>(&body).unwrap()["uuid"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(shown, uuid.to_string());
+        let deleted =
+            controller::delete_data(String::from(SHORT), db.clone(), Some(shown), None, json())
+                .await;
+        assert!(deleted.is_err());
+        assert!(db.get_record(uuid).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
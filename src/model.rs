@@ -1,18 +1,33 @@
-use std::str::from_utf8;
+use std::{fmt, io, sync::Arc};
 
 use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sled::transaction;
 use sled::Transactional;
-use transaction::{ConflictableTransactionError, TransactionalTree};
+use transaction::{
+    ConflictableTransactionError, TransactionError, TransactionalTree, UnabortableTransactionError,
+};
 use uuid::Uuid;
 
 use crate::base32;
+use crate::blob::BlobStore;
+use crate::migration;
+
+const SHORT_LEN: usize = 4;
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Hash, PartialEq, Eq)]
 pub enum TreeNames {
     DataTree,
     ShortNameTree,
     CustomNameTree,
+    ExpiryTree,
+    ContentTree,
+    UserTree,
+    ApiKeyTree,
+    OwnerTree,
+    StatsTree,
+    MetaTree,
 }
 
 impl AsRef<[u8]> for TreeNames {
@@ -21,6 +36,13 @@ impl AsRef<[u8]> for TreeNames {
             TreeNames::DataTree => &[0],
             TreeNames::ShortNameTree => &[1],
             TreeNames::CustomNameTree => &[2],
+            TreeNames::ExpiryTree => &[3],
+            TreeNames::ContentTree => &[4],
+            TreeNames::UserTree => &[5],
+            TreeNames::ApiKeyTree => &[6],
+            TreeNames::OwnerTree => &[7],
+            TreeNames::StatsTree => &[8],
+            TreeNames::MetaTree => &[9],
         }
     }
 }
@@ -29,44 +51,283 @@ pub struct DataTrees {
     pub db: sled::Tree,
     pub short_to_uuid_db: sled::Tree,
     pub custom_to_uuid_db: sled::Tree,
+    pub expiry_to_uuid_db: sled::Tree,
+    pub content_db: sled::Tree,
+    pub user_db: sled::Tree,
+    pub api_key_to_user_db: sled::Tree,
+    pub owner_to_uuid_db: sled::Tree,
+    pub stats_db: sled::Tree,
+    /// bookkeeping of the database itself, like its schema version
+    pub meta_db: sled::Tree,
+    pub blobs: BlobStore,
 }
 
 impl DataTrees {
-    pub fn new(database: sled::Db) -> Self {
-        DataTrees {
-            db: database.open_tree(TreeNames::DataTree).unwrap(),
-            short_to_uuid_db: database.open_tree(TreeNames::ShortNameTree).unwrap(),
-            custom_to_uuid_db: database.open_tree(TreeNames::CustomNameTree).unwrap(),
+    pub fn new(database: sled::Db, blobs: BlobStore) -> Result<Self, DataBaseErrorType> {
+        let trees = DataTrees {
+            db: database.open_tree(TreeNames::DataTree)?,
+            short_to_uuid_db: database.open_tree(TreeNames::ShortNameTree)?,
+            custom_to_uuid_db: database.open_tree(TreeNames::CustomNameTree)?,
+            expiry_to_uuid_db: database.open_tree(TreeNames::ExpiryTree)?,
+            content_db: database.open_tree(TreeNames::ContentTree)?,
+            user_db: database.open_tree(TreeNames::UserTree)?,
+            api_key_to_user_db: database.open_tree(TreeNames::ApiKeyTree)?,
+            owner_to_uuid_db: database.open_tree(TreeNames::OwnerTree)?,
+            stats_db: database.open_tree(TreeNames::StatsTree)?,
+            meta_db: database.open_tree(TreeNames::MetaTree)?,
+            blobs,
+        };
+        migration::run(&trees)
+            .map_err(|e| DataBaseErrorType::Io(format!("migrating the database failed: {}", e)))?;
+        if trees.expiry_to_uuid_db.is_empty() && !trees.db.is_empty() {
+            trees.rebuild_expiry_index()?;
+        }
+        Ok(trees)
+    }
+
+    /// backfill the expiry index for databases created before it existed
+    fn rebuild_expiry_index(&self) -> Result<(), DataBaseErrorType> {
+        for value in self.db.iter().values() {
+            // unreadable records are left for fsck
+            if let Some(record) = decode::<Record>(&value?) {
+                if let Some(t) = record.destroy_time {
+                    self.expiry_to_uuid_db
+                        .insert(expiry_key(t, &record.uuid), record.uuid.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// how records, contents, users and view statistics are stored: the bincode of the value along with
+/// the version of its layout, so that values written by an older rspb can
+/// still be told apart and read
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    payload: Vec<u8>,
+}
+
+/// values kept in an `Envelope`
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// bump this, and teach `upgrade` the previous layout, whenever the
+    /// layout changes
+    const VERSION: u32;
+
+    /// read a value stored with an older layout
+    fn upgrade(version: u32, payload: &[u8]) -> Option<Self> {
+        let _ = (version, payload);
+        None
+    }
+}
+
+pub fn encode<T: Versioned>(value: &T) -> Result<Vec<u8>, DataBaseErrorType> {
+    let envelope = Envelope {
+        version: T::VERSION,
+        payload: bincode::serialize(value)?,
+    };
+    Ok(bincode::serialize(&envelope)?)
+}
+
+pub fn decode<T: Versioned>(bytes: &[u8]) -> Option<T> {
+    let envelope = bincode::deserialize::<Envelope>(bytes).ok()?;
+    if envelope.version == T::VERSION {
+        bincode::deserialize(&envelope.payload).ok()
+    } else {
+        T::upgrade(envelope.version, &envelope.payload)
+    }
+}
+
+/// take a reference to the content under `key` for the paste `uuid`, storing
+/// `data` there if no other paste has this content yet
+pub fn acquire_content(
+    content_db: &TransactionalTree,
+    uuid: &Uuid,
+    key: &str,
+    data: &DataType,
+) -> Result<(), ConflictableTransactionError<DataBaseErrorType>> {
+    let content = match content_db.get(key.as_bytes())? {
+        Some(v) => {
+            let mut content = decode::<SharedContent>(&v).ok_or_else(|| abort(corrupt(uuid)))?;
+            content.refs += 1;
+            content
         }
+        None => SharedContent::new(data.clone()),
+    };
+    content_db.insert(key.as_bytes(), encode(&content).map_err(abort)?)?;
+    Ok(())
+}
+
+/// drop the reference of the paste `uuid` to the content under `key`,
+/// returning the content when this was the last one
+fn release_content(
+    content_db: &TransactionalTree,
+    uuid: &Uuid,
+    key: &str,
+) -> Result<Option<DataType>, ConflictableTransactionError<DataBaseErrorType>> {
+    let mut content = match content_db.get(key.as_bytes())? {
+        Some(v) => decode::<SharedContent>(&v).ok_or_else(|| abort(corrupt(uuid)))?,
+        None => return Ok(None),
+    };
+    content.refs = content.refs.saturating_sub(1);
+    if content.refs == 0 {
+        content_db.remove(key.as_bytes())?;
+        return Ok(Some(content.data));
+    }
+    content_db.insert(key.as_bytes(), encode(&content).map_err(abort)?)?;
+    Ok(None)
+}
+
+fn abort(e: DataBaseErrorType) -> ConflictableTransactionError<DataBaseErrorType> {
+    ConflictableTransactionError::Abort(e)
+}
+
+fn corrupt(uuid: &Uuid) -> DataBaseErrorType {
+    DataBaseErrorType::Corrupt(*uuid)
+}
+
+/// key the content `data` hashing to `hash` is shared under
+pub fn content_key(hash: &str, data: &DataType) -> String {
+    match data {
+        DataType::ShortLink(_) => format!("{}.link", hash),
+        _ => hash.to_string(),
     }
 }
 
+/// big-endian millisecond timestamp followed by the uuid, so that keys sort
+/// by destroy time and two pastes expiring at the same instant don't collide
+pub fn expiry_key(time: DateTime<Utc>, uuid: &Uuid) -> Vec<u8> {
+    let millis = time.timestamp_millis().max(0) as u64;
+    let mut key = Vec::with_capacity(24);
+    key.extend_from_slice(&millis.to_be_bytes());
+    key.extend_from_slice(uuid.as_bytes());
+    key
+}
+
+/// the owner's name and a nul byte, then the creation time and uuid laid out
+/// like `expiry_key`, so that the pastes of one owner sort by creation time
+pub fn owner_key(owner: &str, created_at: DateTime<Utc>, uuid: &Uuid) -> Vec<u8> {
+    let mut key = owner_prefix(owner);
+    key.extend_from_slice(&expiry_key(created_at, uuid));
+    key
+}
+
+fn owner_prefix(owner: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(owner.len() + 25);
+    key.extend_from_slice(owner.as_bytes());
+    key.push(0);
+    key
+}
+
+/// where a listing left off: creation time and uuid of the last paste seen
+pub type Cursor = (DateTime<Utc>, Uuid);
+
 #[derive(Debug)]
 pub enum DataBaseErrorType {
-    Existed(DataBaseItem),
+    Existed(Box<DataBaseItem>),
     Failed,
     NotFound,
+    /// the storage couldn't be read or written
+    Io(String),
+    /// the paste with this uuid, or the content it refers to, is stored but
+    /// can't be read
+    Corrupt(Uuid),
+    /// a value couldn't be turned into bytes, or an index entry back into a
+    /// value
+    Serialization(String),
+}
+
+impl fmt::Display for DataBaseErrorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataBaseErrorType::Existed(_) => write!(f, "already exists"),
+            DataBaseErrorType::Failed => write!(f, "database operation failed"),
+            DataBaseErrorType::NotFound => write!(f, "not found"),
+            DataBaseErrorType::Io(e) => write!(f, "storage failed: {}", e),
+            DataBaseErrorType::Corrupt(uuid) => write!(f, "paste {} is corrupt", uuid),
+            DataBaseErrorType::Serialization(e) => write!(f, "serialization failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DataBaseErrorType {}
+
+impl From<sled::Error> for DataBaseErrorType {
+    fn from(e: sled::Error) -> Self {
+        DataBaseErrorType::Io(e.to_string())
+    }
+}
+
+impl From<io::Error> for DataBaseErrorType {
+    fn from(e: io::Error) -> Self {
+        DataBaseErrorType::Io(e.to_string())
+    }
+}
+
+impl From<bincode::Error> for DataBaseErrorType {
+    fn from(e: bincode::Error) -> Self {
+        DataBaseErrorType::Serialization(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for DataBaseErrorType {
+    fn from(e: serde_json::Error) -> Self {
+        DataBaseErrorType::Serialization(e.to_string())
+    }
+}
+
+impl From<TransactionError<DataBaseErrorType>> for DataBaseErrorType {
+    fn from(e: TransactionError<DataBaseErrorType>) -> Self {
+        match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DataType {
     Text(String),
     ShortLink(String),
     Binary(Vec<u8>),
+    /// content kept in the blob store under its digest
+    Blob {
+        digest: String,
+        size: u64,
+        text: bool,
+    },
 }
 
 impl DataType {
+    /// content stored inline, which is empty for blobs
     pub fn get_data(&self) -> &[u8] {
         match self {
             DataType::Text(t) => t.as_bytes(),
             DataType::ShortLink(t) => t.as_bytes(),
             DataType::Binary(t) => t,
+            DataType::Blob { .. } => &[],
+        }
+    }
+
+    /// length of the content, wherever it is kept
+    pub fn size(&self) -> u64 {
+        match self {
+            DataType::Blob { size, .. } => *size,
+            _ => self.get_data().len() as u64,
+        }
+    }
+
+    pub fn blob_digest(&self) -> Option<&str> {
+        match self {
+            DataType::Blob { digest, .. } => Some(digest),
+            _ => None,
         }
     }
 
     pub fn from_bytes(data: Vec<u8>, is_short_link: Option<bool>) -> Option<DataType> {
         let short_link = is_short_link.unwrap_or(false);
-        let d = String::from_utf8(data.clone());
+        let d = String::from_utf8(data);
         match d {
             Ok(str) => {
                 if short_link {
@@ -79,27 +340,98 @@ impl DataType {
                     Some(DataType::Text(str))
                 }
             }
-            Err(_) => {
+            Err(e) => {
                 if short_link {
                     None
                 } else {
-                    Some(DataType::Binary(data))
+                    Some(DataType::Binary(e.into_bytes()))
                 }
             }
         }
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DataBaseItem {
     pub destroy_time: Option<DateTime<Utc>>,
+    /// views after which the paste is deleted
+    pub max_views: Option<u64>,
     pub custom_url: Option<String>,
     pub uuid: Uuid,
     pub hash: String,
     pub short: String,
+    /// blake3 of the secret needed to update or delete the paste
+    pub secret_hash: String,
+    /// name of the user who uploaded the paste, if they were logged in
+    pub owner: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// when the content was last replaced, or the creation time
+    pub updated_at: DateTime<Utc>,
+    pub stats: ViewStats,
     pub data: DataType,
 }
 
+/// how often and when a paste was last viewed, kept apart from the paste so
+/// that counting a view doesn't rewrite it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ViewStats {
+    pub views: u64,
+    pub last_viewed_at: Option<DateTime<Utc>>,
+}
+
+impl Versioned for ViewStats {
+    const VERSION: u32 = 1;
+}
+
+impl ViewStats {
+    pub fn record_view(&mut self, at: DateTime<Utc>) {
+        self.views += 1;
+        self.last_viewed_at = Some(at);
+    }
+}
+
+/// a random token for paste secrets and api keys
+pub fn generate_secret() -> String {
+    base32::encode(&rand::random::<[u8; 20]>())
+}
+
+/// secrets are random enough for a plain hash to be safe to keep around
+pub fn hash_secret(secret: &str) -> String {
+    String::from(blake3::hash(secret.as_bytes()).to_hex().as_str())
+}
+
+/// someone allowed to upload pastes under their name with an api key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    /// blake3 of the api key
+    pub key_hash: String,
+    /// admins may manage every paste and create users
+    pub admin: bool,
+}
+
+impl Versioned for User {
+    const VERSION: u32 = 1;
+}
+
+impl User {
+    /// a new user along with their api key, of which only the hash is kept
+    pub fn new(name: String, admin: bool) -> (User, String) {
+        let key = generate_secret();
+        let user = User {
+            name,
+            key_hash: hash_secret(&key),
+            admin,
+        };
+        (user, key)
+    }
+
+    /// whether this user may update or delete `item`
+    pub fn manages(&self, item: &DataBaseItem) -> bool {
+        self.admin || item.owner.as_deref() == Some(self.name.as_str())
+    }
+}
+
 impl DataBaseItem {
     pub fn new(
         data: DataType,
@@ -107,18 +439,154 @@ impl DataBaseItem {
         destroy_time: Option<DateTime<Utc>>,
     ) -> DataBaseItem {
         let hash = blake3::hash(data.get_data());
-        let short = &base32::encode(hash.as_bytes())[0..4];
+        DataBaseItem::with_hash(data, hash, custom_url, destroy_time)
+    }
+
+    /// like `new`, for content whose hash was already computed while receiving it
+    pub fn with_hash(
+        data: DataType,
+        hash: blake3::Hash,
+        custom_url: Option<String>,
+        destroy_time: Option<DateTime<Utc>>,
+    ) -> DataBaseItem {
+        let short = &base32::encode(hash.as_bytes())[0..SHORT_LEN];
+        let now = Utc::now();
         DataBaseItem {
             destroy_time,
+            max_views: None,
             custom_url,
             data,
             short: String::from(short),
             hash: String::from(hash.to_hex().as_str()),
             uuid: Uuid::new_v4(),
+            secret_hash: String::new(),
+            owner: None,
+            created_at: now,
+            updated_at: now,
+            stats: ViewStats::default(),
+        }
+    }
+
+    /// give the paste a new random secret, returning it; only its hash is kept
+    pub fn reset_secret(&mut self) -> String {
+        let secret = generate_secret();
+        self.secret_hash = hash_secret(&secret);
+        secret
+    }
+
+    pub fn check_secret(&self, secret: &str) -> bool {
+        match blake3::Hash::from_hex(&self.secret_hash) {
+            // comparing blake3 hashes takes constant time
+            Ok(expected) => blake3::hash(secret.as_bytes()) == expected,
+            Err(_) => false,
+        }
+    }
+
+    /// prefixes of the encoded hash, shortest first, to fall back on when the
+    /// default short id is taken by a paste with different content
+    pub fn short_candidates(&self) -> Vec<String> {
+        let encoded = blake3::Hash::from_hex(&self.hash)
+            .map(|hash| base32::encode(hash.as_bytes()))
+            .unwrap_or_else(|_| self.short.clone());
+        (SHORT_LEN..=encoded.len())
+            .map(|len| String::from(&encoded[..len]))
+            .collect()
+    }
+
+    /// whether uploading this should give back `existing` instead of a new
+    /// paste; uploads with a custom url or by someone else always get their
+    /// own paste, sharing the content with the existing one
+    pub fn is_duplicate_of(&self, existing: &DataBaseItem) -> bool {
+        // handing out a paste that burns after reading to someone else, or the
+        // other way around, would burn it early
+        self.custom_url.is_none()
+            && self.max_views.is_none()
+            && existing.max_views.is_none()
+            && self.hash == existing.hash
+            && self.owner == existing.owner
+    }
+
+    /// key the content is shared under, links are kept apart from pastes of
+    /// the same text
+    pub fn content_key(&self) -> String {
+        content_key(&self.hash, &self.data)
+    }
+
+    pub fn record(&self) -> Record {
+        Record {
+            destroy_time: self.destroy_time,
+            max_views: self.max_views,
+            custom_url: self.custom_url.clone(),
+            uuid: self.uuid,
+            hash: self.hash.clone(),
+            short: self.short.clone(),
+            secret_hash: self.secret_hash.clone(),
+            owner: self.owner.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            content: self.content_key(),
+        }
+    }
+}
+
+/// a paste as the backends keep it, with the content stored apart so that
+/// every paste with the same content shares a single copy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub destroy_time: Option<DateTime<Utc>>,
+    pub max_views: Option<u64>,
+    pub custom_url: Option<String>,
+    pub uuid: Uuid,
+    pub hash: String,
+    pub short: String,
+    pub secret_hash: String,
+    pub owner: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// key of the shared content
+    pub content: String,
+}
+
+impl Versioned for Record {
+    const VERSION: u32 = 1;
+}
+
+impl Record {
+    pub fn into_item(self, data: DataType, stats: ViewStats) -> DataBaseItem {
+        DataBaseItem {
+            destroy_time: self.destroy_time,
+            max_views: self.max_views,
+            custom_url: self.custom_url,
+            uuid: self.uuid,
+            hash: self.hash,
+            short: self.short,
+            secret_hash: self.secret_hash,
+            owner: self.owner,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            stats,
+            data,
         }
     }
 }
 
+/// content along with the number of pastes using it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SharedContent {
+    pub refs: u64,
+    pub data: DataType,
+}
+
+impl Versioned for SharedContent {
+    const VERSION: u32 = 1;
+}
+
+impl SharedContent {
+    pub fn new(data: DataType) -> Self {
+        SharedContent { refs: 1, data }
+    }
+}
+
 // fn insert_when_not_exist_cas<K: AsRef<[u8]>, V: Into<IVec>>(
 //     db: sled::Tree,
 //     key: K,
@@ -146,129 +614,599 @@ impl DataBaseItem {
 //     return Err(ConflictableTransactionError::Conflict);
 // }
 
-pub fn add_record(db: DataTrees, data: &DataBaseItem) -> Result<(), DataBaseErrorType> {
-    if search_key_in_db(db.clone(), data.uuid.as_bytes()).is_ok() {
-        return Err(DataBaseErrorType::Existed(
-            get_data_in_db(db, data.uuid.as_bytes()).unwrap(),
-        ));
-    } else if search_key_in_db(db.clone(), data.short.as_bytes()).is_ok() {
-        return Err(DataBaseErrorType::Existed(
-            get_data_in_db(db, data.short.as_bytes()).unwrap(),
-        ));
-    } else if let Some(str) = &data.custom_url {
-        if search_key_in_db(db.clone(), str.as_bytes()).is_ok() {
-            return Err(DataBaseErrorType::Existed(
-                get_data_in_db(db, str.as_bytes()).unwrap(),
-            ));
-        }
+/// storage backend for pastes, which are looked up by short id, custom url
+/// or uuid
+pub trait Store: Send + Sync {
+    /// where contents too large for the backend itself are kept
+    fn blobs(&self) -> &BlobStore;
+
+    /// insert a new paste, allocating its short id
+    fn add_record(&self, data: &mut DataBaseItem) -> Result<(), DataBaseErrorType>;
+
+    /// insert a paste as it was exported, keeping its short id and view
+    /// statistics, reporting the paste in the way when its uuid, short id or
+    /// custom url is taken
+    fn restore_record(&self, data: &DataBaseItem) -> Result<(), DataBaseErrorType>;
+
+    /// look up a paste by one of its public keys, its short id or custom url
+    fn query_record(&self, key: &str) -> Result<DataBaseItem, DataBaseErrorType>;
+
+    /// look up a paste by its uuid, which isn't a public key
+    fn get_record(&self, key: Uuid) -> Result<DataBaseItem, DataBaseErrorType>;
+
+    /// swap the content of a paste, returning the previous content when no
+    /// other paste shares it
+    fn replace_data(
+        &self,
+        key: Uuid,
+        value: DataType,
+        hash: blake3::Hash,
+    ) -> Result<Option<DataType>, DataBaseErrorType>;
+
+    /// change when a paste expires, never when `None`
+    fn set_expiry(
+        &self,
+        key: Uuid,
+        destroy_time: Option<DateTime<Utc>>,
+    ) -> Result<(), DataBaseErrorType>;
+
+    /// remove a paste along with every index entry pointing at it, returning
+    /// its content when no other paste shares it
+    fn remove_record(&self, key: Uuid) -> Result<Option<DataType>, DataBaseErrorType>;
+
+    /// uuids of the pastes whose destroy time is before `now`
+    fn expired_records(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>, DataBaseErrorType>;
+
+    /// count a view of a paste, returning how many it has had
+    fn record_view(&self, key: Uuid, at: DateTime<Utc>) -> Result<u64, DataBaseErrorType>;
+
+    /// pastes of `owner`, newest first, starting after `after` when given
+    fn list_records(
+        &self,
+        owner: &str,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<DataBaseItem>, DataBaseErrorType>;
+
+    /// register a user, false when the name is already taken
+    fn add_user(&self, user: &User) -> Result<bool, DataBaseErrorType>;
+
+    /// the user whose api key hashes to `key_hash`
+    fn find_user(&self, key_hash: &str) -> Result<User, DataBaseErrorType>;
+
+    /// uuids of every paste
+    fn uuids(&self) -> Result<Vec<Uuid>, DataBaseErrorType>;
+
+    /// every registered user
+    fn users(&self) -> Result<Vec<User>, DataBaseErrorType>;
+
+    /// make sure everything written so far survives the process exiting
+    fn flush(&self) -> Result<(), DataBaseErrorType> {
+        Ok(())
     }
-    let res = (&db.db, &db.short_to_uuid_db, &db.custom_to_uuid_db).transaction(
-        |(db, short_to_long_db, custom_to_long_db): &(
-            TransactionalTree,
-            TransactionalTree,
-            TransactionalTree,
-        )|
-         -> Result<(), ConflictableTransactionError> {
-            db.insert(data.uuid.as_bytes(), bincode::serialize(&data).unwrap())?;
-            short_to_long_db.insert(data.short.as_bytes(), data.uuid.as_bytes())?;
-            if let Some(special_url) = &data.custom_url {
-                custom_to_long_db.insert(special_url.as_bytes(), data.uuid.as_bytes())?;
-            }
-            Ok(())
-        },
-    );
-    if res.is_err() {
-        return Err(DataBaseErrorType::Failed);
+
+    fn delete_record(&self, key: Uuid) -> Result<(), DataBaseErrorType> {
+        let _blobs = self.blobs().lock();
+        if let Some(old) = self.remove_record(key)? {
+            self.blobs().release(&old);
+        }
+        Ok(())
     }
-    Ok(())
 }
 
-fn search_key_in_db(db: DataTrees, key: &[u8]) -> Result<TreeNames, DataBaseErrorType> {
-    // short -> custom -> uuid
-    if db.short_to_uuid_db.contains_key(key).unwrap_or(false) {
-        return Ok(TreeNames::ShortNameTree);
-    }
-    if db.custom_to_uuid_db.contains_key(key).unwrap_or(false) {
-        return Ok(TreeNames::CustomNameTree);
+pub type Db = Arc<dyn Store>;
+
+impl Store for DataTrees {
+    fn blobs(&self) -> &BlobStore {
+        &self.blobs
     }
-    if let Ok(str) = from_utf8(key) {
-        if let Ok(id) = uuid::Uuid::parse_str(str) {
-            if db.db.contains_key(id.as_bytes()).unwrap_or(false) {
-                return Ok(TreeNames::DataTree);
+
+    fn add_record(&self, data: &mut DataBaseItem) -> Result<(), DataBaseErrorType> {
+        absent(get_item_in_db(self, data.uuid))?;
+        if let Some(str) = &data.custom_url {
+            absent(get_data_in_db(self, str.as_bytes()))?;
+        }
+        loop {
+            data.short = allocate_short(self, data)?;
+            match insert_item(self, data)? {
+                Insertion::Done => return Ok(()),
+                // someone else claimed this short id since we picked it
+                Insertion::ShortTaken => {}
+                Insertion::CustomTaken(url) => return Err(in_the_way(self, &url)),
             }
         }
     }
-    Err(DataBaseErrorType::NotFound)
-}
 
-fn get_data_in_db(db: DataTrees, key: &[u8]) -> Result<DataBaseItem, DataBaseErrorType> {
-    let res = search_key_in_db(db.clone(), key)?;
-    let data: DataBaseItem;
-    match res {
-        TreeNames::DataTree => {
-            data = bincode::deserialize::<DataBaseItem>(
-                &db.db
-                    .get(Uuid::parse_str(from_utf8(key).unwrap()).unwrap().as_bytes())
-                    .unwrap()
-                    .unwrap(),
+    fn restore_record(&self, data: &DataBaseItem) -> Result<(), DataBaseErrorType> {
+        absent(get_item_in_db(self, data.uuid))?;
+        match insert_item(self, data)? {
+            Insertion::Done => Ok(()),
+            Insertion::ShortTaken => Err(in_the_way(self, &data.short)),
+            Insertion::CustomTaken(url) => Err(in_the_way(self, &url)),
+        }
+    }
+
+    fn query_record(&self, key: &str) -> Result<DataBaseItem, DataBaseErrorType> {
+        get_data_in_db(self, key.as_bytes())
+    }
+
+    fn get_record(&self, key: Uuid) -> Result<DataBaseItem, DataBaseErrorType> {
+        get_item_in_db(self, key)
+    }
+
+    fn replace_data(
+        &self,
+        key: Uuid,
+        value: DataType,
+        hash: blake3::Hash,
+    ) -> Result<Option<DataType>, DataBaseErrorType> {
+        let mut data = get_item_in_db(self, key)?;
+        let old_content = data.content_key();
+        data.data = value;
+        data.hash = String::from(hash.to_hex().as_str());
+        data.updated_at = Utc::now();
+        let record = data.record();
+        let encoded = encode(&record)?;
+        let res = (&self.db, &self.content_db).transaction(
+            |(db, content_db): &(TransactionalTree, TransactionalTree)|
+             -> Result<Option<DataType>, ConflictableTransactionError<DataBaseErrorType>> {
+                db.insert(key.as_bytes(), encoded.as_slice())?;
+                if record.content == old_content {
+                    return Ok(None);
+                }
+                acquire_content(content_db, &key, &record.content, &data.data)?;
+                release_content(content_db, &key, &old_content)
+            },
+        );
+        Ok(res?)
+    }
+
+    fn set_expiry(
+        &self,
+        key: Uuid,
+        destroy_time: Option<DateTime<Utc>>,
+    ) -> Result<(), DataBaseErrorType> {
+        let mut record = get_record_in_db(self, key)?;
+        let old_time = record.destroy_time;
+        record.destroy_time = destroy_time;
+        let encoded = encode(&record)?;
+        let res = (&self.db, &self.expiry_to_uuid_db).transaction(
+            |(db, expiry_db): &(TransactionalTree, TransactionalTree)|
+             -> Result<(), ConflictableTransactionError<DataBaseErrorType>> {
+                if let Some(t) = old_time {
+                    expiry_db.remove(expiry_key(t, &key))?;
+                }
+                if let Some(t) = destroy_time {
+                    expiry_db.insert(expiry_key(t, &key), key.as_bytes())?;
+                }
+                db.insert(key.as_bytes(), encoded.as_slice())?;
+                Ok(())
+            },
+        );
+        Ok(res?)
+    }
+
+    fn remove_record(&self, key: Uuid) -> Result<Option<DataType>, DataBaseErrorType> {
+        let data = get_record_in_db(self, key)?;
+        let res =
+            (
+                &self.db,
+                &self.short_to_uuid_db,
+                &self.custom_to_uuid_db,
+                &self.expiry_to_uuid_db,
+                &self.content_db,
+                &self.owner_to_uuid_db,
+                &self.stats_db,
             )
-            .unwrap();
+                .transaction(
+                    |(
+                        db,
+                        short_to_long_db,
+                        custom_to_long_db,
+                        expiry_db,
+                        content_db,
+                        owner_db,
+                        stats_db,
+                    ): &(
+                        TransactionalTree,
+                        TransactionalTree,
+                        TransactionalTree,
+                        TransactionalTree,
+                        TransactionalTree,
+                        TransactionalTree,
+                        TransactionalTree,
+                    )|
+                     -> Result<
+                        Option<DataType>,
+                        ConflictableTransactionError<DataBaseErrorType>,
+                    > {
+                        if db.remove(key.as_bytes())?.is_none() {
+                            // removed since it was read, its content is released already
+                            return Err(abort(DataBaseErrorType::NotFound));
+                        }
+                        short_to_long_db.remove(data.short.as_bytes())?;
+                        if let Some(url) = &data.custom_url {
+                            custom_to_long_db.remove(url.as_bytes())?;
+                        }
+                        if let Some(t) = data.destroy_time {
+                            expiry_db.remove(expiry_key(t, &key))?;
+                        }
+                        if let Some(owner) = &data.owner {
+                            owner_db.remove(owner_key(owner, data.created_at, &key))?;
+                        }
+                        stats_db.remove(key.as_bytes())?;
+                        release_content(content_db, &key, &data.content)
+                    },
+                );
+        Ok(res?)
+    }
+
+    fn expired_records(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        self.expiry_to_uuid_db
+            .range(..expiry_key(now, &Uuid::nil()))
+            .values()
+            .map(|uuid| index_uuid(&uuid?))
+            .collect()
+    }
+
+    fn record_view(&self, key: Uuid, at: DateTime<Utc>) -> Result<u64, DataBaseErrorType> {
+        if !self.db.contains_key(key.as_bytes())? {
+            return Err(DataBaseErrorType::NotFound);
         }
-        TreeNames::ShortNameTree => {
-            let key = db.short_to_uuid_db.get(key).unwrap().unwrap();
-            data = bincode::deserialize::<DataBaseItem>(&db.db.get(key).unwrap().unwrap()).unwrap();
+        let stats = self.stats_db.update_and_fetch(key.as_bytes(), |old| {
+            let mut stats = old.and_then(decode::<ViewStats>).unwrap_or_default();
+            stats.record_view(at);
+            // keep what is there should the stats somehow not serialize
+            encode(&stats).ok().or_else(|| old.map(|v| v.to_vec()))
+        })?;
+        stats
+            .and_then(|v| decode::<ViewStats>(&v))
+            .map(|stats| stats.views)
+            .ok_or(DataBaseErrorType::Corrupt(key))
+    }
+
+    fn list_records(
+        &self,
+        owner: &str,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<DataBaseItem>, DataBaseErrorType> {
+        let start = owner_prefix(owner);
+        let end = match after {
+            Some((created_at, uuid)) => owner_key(owner, created_at, &uuid),
+            None => {
+                // just past every key starting with the prefix
+                let mut end = owner.as_bytes().to_vec();
+                end.push(1);
+                end
+            }
+        };
+        self.owner_to_uuid_db
+            .range(start..end)
+            .values()
+            .rev()
+            .take(limit)
+            .map(|uuid| get_item_in_db(self, index_uuid(&uuid?)?))
+            .collect()
+    }
+
+    fn add_user(&self, user: &User) -> Result<bool, DataBaseErrorType> {
+        let encoded = encode(user)?;
+        let res = (&self.user_db, &self.api_key_to_user_db).transaction(
+            |(user_db, api_key_db): &(TransactionalTree, TransactionalTree)|
+             -> Result<bool, ConflictableTransactionError<DataBaseErrorType>> {
+                if user_db.get(user.name.as_bytes())?.is_some() {
+                    return Ok(false);
+                }
+                user_db.insert(user.name.as_bytes(), encoded.as_slice())?;
+                api_key_db.insert(user.key_hash.as_bytes(), user.name.as_bytes())?;
+                Ok(true)
+            },
+        );
+        Ok(res?)
+    }
+
+    fn find_user(&self, key_hash: &str) -> Result<User, DataBaseErrorType> {
+        let name = self
+            .api_key_to_user_db
+            .get(key_hash.as_bytes())?
+            .ok_or(DataBaseErrorType::NotFound)?;
+        let user = self
+            .user_db
+            .get(&name)?
+            .ok_or(DataBaseErrorType::NotFound)?;
+        decode::<User>(&user).ok_or_else(|| unreadable_user(&name))
+    }
+
+    fn flush(&self) -> Result<(), DataBaseErrorType> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn uuids(&self) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        self.db.iter().keys().map(|key| index_uuid(&key?)).collect()
+    }
+
+    fn users(&self) -> Result<Vec<User>, DataBaseErrorType> {
+        self.user_db
+            .iter()
+            .map(|entry| {
+                let (name, user) = entry?;
+                decode::<User>(&user).ok_or_else(|| unreadable_user(&name))
+            })
+            .collect()
+    }
+}
+
+/// what became of a paste given to `insert_item`
+enum Insertion {
+    Done,
+    ShortTaken,
+    CustomTaken(String),
+}
+
+/// insert `data` along with its index entries, unless its short id or custom
+/// url is taken by another paste's short id or custom url
+fn insert_item(db: &DataTrees, data: &DataBaseItem) -> Result<Insertion, DataBaseErrorType> {
+    let record = data.record();
+    let value = encode(&record)?;
+    // pastes that were never viewed have no statistics yet
+    let stats = if data.stats == ViewStats::default() {
+        None
+    } else {
+        Some(encode(&data.stats)?)
+    };
+    let res = (
+        &db.db,
+        &db.short_to_uuid_db,
+        &db.custom_to_uuid_db,
+        &db.expiry_to_uuid_db,
+        &db.content_db,
+        &db.owner_to_uuid_db,
+        &db.stats_db,
+    )
+        .transaction(
+            |(
+                db,
+                short_to_long_db,
+                custom_to_long_db,
+                expiry_db,
+                content_db,
+                owner_db,
+                stats_db,
+            ): &(
+                TransactionalTree,
+                TransactionalTree,
+                TransactionalTree,
+                TransactionalTree,
+                TransactionalTree,
+                TransactionalTree,
+                TransactionalTree,
+            )|
+             -> Result<Insertion, ConflictableTransactionError<DataBaseErrorType>> {
+                // both trees are looked up by the same keys
+                let taken = |key: &str| -> Result<bool, UnabortableTransactionError> {
+                    Ok(short_to_long_db.get(key.as_bytes())?.is_some()
+                        || custom_to_long_db.get(key.as_bytes())?.is_some())
+                };
+                if taken(&data.short)? {
+                    return Ok(Insertion::ShortTaken);
+                }
+                if let Some(url) = data.custom_url.as_ref() {
+                    if taken(url)? {
+                        return Ok(Insertion::CustomTaken(url.clone()));
+                    }
+                }
+                db.insert(data.uuid.as_bytes(), value.as_slice())?;
+                acquire_content(content_db, &data.uuid, &record.content, &data.data)?;
+                short_to_long_db.insert(data.short.as_bytes(), data.uuid.as_bytes())?;
+                if let Some(special_url) = &data.custom_url {
+                    custom_to_long_db.insert(special_url.as_bytes(), data.uuid.as_bytes())?;
+                }
+                if let Some(t) = data.destroy_time {
+                    expiry_db.insert(expiry_key(t, &data.uuid), data.uuid.as_bytes())?;
+                }
+                if let Some(owner) = &data.owner {
+                    owner_db.insert(
+                        owner_key(owner, data.created_at, &data.uuid),
+                        data.uuid.as_bytes(),
+                    )?;
+                }
+                if let Some(stats) = &stats {
+                    stats_db.insert(data.uuid.as_bytes(), stats.as_slice())?;
+                }
+                Ok(Insertion::Done)
+            },
+        );
+    Ok(res?)
+}
+
+/// pick the shortest free short id for `data`, reporting an existing paste
+/// instead when one with identical content already owns a candidate
+fn allocate_short(db: &DataTrees, data: &DataBaseItem) -> Result<String, DataBaseErrorType> {
+    for candidate in data.short_candidates() {
+        let key = candidate.as_bytes();
+        if !db.short_to_uuid_db.contains_key(key)? && !db.custom_to_uuid_db.contains_key(key)? {
+            return Ok(candidate);
         }
-        TreeNames::CustomNameTree => {
-            let key = db.custom_to_uuid_db.get(key).unwrap().unwrap();
-            data = bincode::deserialize::<DataBaseItem>(&db.db.get(key).unwrap().unwrap()).unwrap();
+        let existing = get_data_in_db(db, candidate.as_bytes())?;
+        if data.is_duplicate_of(&existing) {
+            return Err(DataBaseErrorType::Existed(Box::new(existing)));
         }
     }
-    Ok(data)
-}
-
-pub fn delete_record(db: DataTrees, key: Uuid) -> Result<(), DataBaseErrorType> {
-    let data = db.db.get(key.as_bytes()).unwrap();
-    if data.is_none() {
-        return Err(DataBaseErrorType::NotFound);
-    }
-    let data = bincode::deserialize::<DataBaseItem>(&data.unwrap()).unwrap();
-    let res = (&db.db, &db.short_to_uuid_db, &db.custom_to_uuid_db).transaction(
-        |(db, short_to_long_db, custom_to_long_db): &(
-            TransactionalTree,
-            TransactionalTree,
-            TransactionalTree,
-        )|
-         -> Result<(), ConflictableTransactionError> {
-            db.remove(key.as_bytes())?;
-            short_to_long_db.remove(data.short.as_bytes())?;
-            if let Some(url) = &data.custom_url {
-                custom_to_long_db.remove(url.as_bytes())?;
-            }
-            Ok(())
-        },
-    );
-    if res.is_err() {
-        return Err(DataBaseErrorType::NotFound);
+    Err(DataBaseErrorType::Failed)
+}
+
+/// fail with the paste `lookup` found, if it found one
+fn absent(lookup: Result<DataBaseItem, DataBaseErrorType>) -> Result<(), DataBaseErrorType> {
+    match lookup {
+        Ok(existing) => Err(DataBaseErrorType::Existed(Box::new(existing))),
+        Err(DataBaseErrorType::NotFound) => Ok(()),
+        Err(e) => Err(e),
     }
-    Ok(())
 }
 
-pub fn query_record(db: DataTrees, key: String) -> Result<DataBaseItem, DataBaseErrorType> {
-    get_data_in_db(db, key.as_bytes())
+/// the paste holding `key` as its short id or custom url
+fn in_the_way(db: &DataTrees, key: &str) -> DataBaseErrorType {
+    match get_data_in_db(db, key.as_bytes()) {
+        Ok(existing) => DataBaseErrorType::Existed(Box::new(existing)),
+        // its index entry points at no paste
+        Err(DataBaseErrorType::NotFound) => DataBaseErrorType::Failed,
+        Err(e) => e,
+    }
+}
+
+fn unreadable_user(name: &[u8]) -> DataBaseErrorType {
+    DataBaseErrorType::Serialization(format!(
+        "user {} can't be read",
+        String::from_utf8_lossy(name)
+    ))
+}
+
+/// the uuid an index entry points at
+fn index_uuid(value: &[u8]) -> Result<Uuid, DataBaseErrorType> {
+    Uuid::from_slice(value).map_err(|e| DataBaseErrorType::Serialization(e.to_string()))
 }
 
-pub fn update_record(db: DataTrees, key: Uuid, value: DataType) -> Result<(), DataBaseErrorType> {
-    let mut data = get_data_in_db(db.clone(), key.to_string().as_bytes())?;
-    data.data = value;
-    data.hash = String::from(blake3::hash(data.data.get_data()).to_hex().as_str());
-    let res = db
-        .db
-        .transaction(|db| -> Result<(), ConflictableTransactionError> {
-            db.insert(key.as_bytes(), bincode::serialize(&data).unwrap())?;
-            Ok(())
-        });
-    if res.is_err() {
-        return Err(DataBaseErrorType::Failed);
+fn get_data_in_db(db: &DataTrees, key: &[u8]) -> Result<DataBaseItem, DataBaseErrorType> {
+    // short -> custom
+    let uuid = match db.short_to_uuid_db.get(key)? {
+        Some(uuid) => uuid,
+        None => db
+            .custom_to_uuid_db
+            .get(key)?
+            .ok_or(DataBaseErrorType::NotFound)?,
+    };
+    get_item_in_db(db, index_uuid(&uuid)?)
+}
+
+fn get_record_in_db(db: &DataTrees, uuid: Uuid) -> Result<Record, DataBaseErrorType> {
+    match db.db.get(uuid.as_bytes())? {
+        Some(record) => decode::<Record>(&record).ok_or(DataBaseErrorType::Corrupt(uuid)),
+        None => Err(DataBaseErrorType::NotFound),
+    }
+}
+
+fn get_item_in_db(db: &DataTrees, uuid: Uuid) -> Result<DataBaseItem, DataBaseErrorType> {
+    let record = get_record_in_db(db, uuid)?;
+    let content = db
+        .content_db
+        .get(record.content.as_bytes())?
+        .and_then(|content| decode::<SharedContent>(&content))
+        .ok_or(DataBaseErrorType::Corrupt(uuid))?;
+    // a paste reads fine without its view statistics
+    let stats = db
+        .stats_db
+        .get(uuid.as_bytes())?
+        .and_then(|v| decode::<ViewStats>(&v))
+        .unwrap_or_default();
+    Ok(record.into_item(content.data, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryStore;
+
+    /// an empty store of each kind that keeps nothing once dropped, but for
+    /// its blobs, which `clean` removes
+    fn stores() -> Vec<Db> {
+        let blobs = || {
+            let dir = std::env::temp_dir().join(format!("rspb-model-{}", Uuid::new_v4()));
+            BlobStore::new(dir, 16).unwrap()
+        };
+        let sled = sled::Config::new().temporary(true).open().unwrap();
+        vec![
+            Arc::new(MemoryStore::new(blobs())),
+            Arc::new(DataTrees::new(sled, blobs()).unwrap()),
+        ]
+    }
+
+    fn clean(db: &Db) {
+        std::fs::remove_dir_all(&db.blobs().dir).unwrap();
+    }
+
+    fn text(content: &str) -> DataBaseItem {
+        DataBaseItem::new(DataType::Text(String::from(content)), None, None)
+    }
+
+    #[test]
+    fn short_ids_grow_past_other_pastes() {
+        for db in stores() {
+            let mut item = text("hello");
+            let taken = item.short.clone();
+            let mut other = text("someone else's");
+            other.short = taken.clone();
+            db.restore_record(&other).unwrap();
+            db.add_record(&mut item).unwrap();
+            assert_eq!(item.short, item.short_candidates()[1]);
+            assert_eq!(db.query_record(&taken).unwrap().uuid, other.uuid);
+            assert_eq!(db.query_record(&item.short).unwrap().uuid, item.uuid);
+            // only the same content gives back the paste having it
+            match db.add_record(&mut text("hello")) {
+                Err(DataBaseErrorType::Existed(existing)) => assert_eq!(existing.uuid, item.uuid),
+                res => panic!("uploaded again: {:?}", res),
+            }
+            clean(&db);
+        }
+    }
+
+    #[test]
+    fn contents_are_released_with_their_last_paste() {
+        let content = b"shared by two pastes";
+        let hash = blake3::hash(content);
+        for db in stores() {
+            let digest = hash.to_hex().to_string();
+            db.blobs().write(&digest, &mut &content[..]).unwrap();
+            let data = DataType::Blob {
+                digest: digest.clone(),
+                size: content.len() as u64,
+                text: true,
+            };
+            // a custom url gets its own paste, sharing the content
+            let mut first = DataBaseItem::with_hash(data.clone(), hash, None, None);
+            let mut second = DataBaseItem::with_hash(data, hash, Some(String::from("x")), None);
+            db.add_record(&mut first).unwrap();
+            db.add_record(&mut second).unwrap();
+            db.delete_record(first.uuid).unwrap();
+            assert!(db.blobs().path(&digest).exists());
+            assert_eq!(db.query_record("x").unwrap().uuid, second.uuid);
+            db.delete_record(second.uuid).unwrap();
+            assert!(!db.blobs().path(&digest).exists());
+            clean(&db);
+        }
+    }
+
+    #[test]
+    fn listings_page_by_cursor() {
+        let start = Utc::now();
+        for db in stores() {
+            let mut uuids = Vec::new();
+            for i in 0..5 {
+                let mut item = text(&format!("paste {}", i));
+                item.owner = Some(String::from("alice"));
+                item.created_at = start + chrono::Duration::seconds(i);
+                db.add_record(&mut item).unwrap();
+                uuids.push(item.uuid);
+            }
+            let mut other = text("bob's");
+            other.owner = Some(String::from("bob"));
+            db.add_record(&mut other).unwrap();
+            let mut listed = Vec::new();
+            let mut after = None;
+            loop {
+                let page = db.list_records("alice", after, 2).unwrap();
+                assert!(page.len() <= 2);
+                match page.last() {
+                    Some(last) => after = Some((last.created_at, last.uuid)),
+                    None => break,
+                }
+                listed.extend(page.iter().map(|item| item.uuid));
+            }
+            // newest first
+            uuids.reverse();
+            assert_eq!(listed, uuids);
+            clean(&db);
+        }
     }
-    Ok(())
 }
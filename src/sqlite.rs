@@ -0,0 +1,512 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::prelude::*;
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use uuid::Uuid;
+
+use crate::blob::BlobStore;
+use crate::model::{Cursor, DataBaseErrorType, DataBaseItem, DataType, Store, User, ViewStats};
+
+/// one row per paste, pointing at its content which is stored once for every
+/// paste sharing it, inline unless it lives in the blob store
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS contents (
+    key TEXT PRIMARY KEY NOT NULL,
+    -- number of pastes using this content
+    refs INTEGER NOT NULL,
+    -- text, link or binary
+    kind TEXT NOT NULL,
+    content BLOB,
+    -- digest of the blob holding the content when it isn't inline
+    blob TEXT,
+    size INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pastes (
+    uuid TEXT PRIMARY KEY NOT NULL,
+    short TEXT NOT NULL UNIQUE,
+    custom_url TEXT UNIQUE,
+    hash TEXT NOT NULL,
+    -- unix time in milliseconds
+    destroy_time INTEGER,
+    -- views after which the paste is deleted
+    max_views INTEGER,
+    -- blake3 of the secret needed to update or delete the paste
+    secret_hash TEXT NOT NULL,
+    owner TEXT REFERENCES users (name),
+    -- unix time in milliseconds
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    last_viewed_at INTEGER,
+    views INTEGER NOT NULL DEFAULT 0,
+    content_key TEXT NOT NULL REFERENCES contents (key)
+);
+CREATE INDEX IF NOT EXISTS pastes_destroy_time ON pastes (destroy_time);
+CREATE INDEX IF NOT EXISTS pastes_owner ON pastes (owner, created_at, uuid);
+CREATE TABLE IF NOT EXISTS users (
+    name TEXT PRIMARY KEY NOT NULL,
+    -- blake3 of the api key
+    key_hash TEXT NOT NULL UNIQUE,
+    admin INTEGER NOT NULL
+);
+";
+
+const COLUMNS: &str = "p.uuid, p.short, p.custom_url, p.hash, p.destroy_time, p.secret_hash, \
+    p.owner, p.created_at, p.updated_at, p.last_viewed_at, p.views, p.max_views, c.kind, c.content, c.blob, c.size";
+
+/// pastes in a single sqlite file, readable with the usual sql tooling
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    blobs: BlobStore,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path, blobs: BlobStore) -> Result<Self, DataBaseErrorType> {
+        let conn = Connection::open(path).map_err(failed)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(failed)?;
+        conn.execute_batch(SCHEMA).map_err(failed)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+            blobs,
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn failed(e: rusqlite::Error) -> DataBaseErrorType {
+    match e {
+        // rows that can't be read carry what is wrong with them
+        rusqlite::Error::FromSqlConversionFailure(column, kind, cause) => {
+            match cause.downcast::<DataBaseErrorType>() {
+                Ok(e) => *e,
+                Err(cause) => DataBaseErrorType::Io(
+                    rusqlite::Error::FromSqlConversionFailure(column, kind, cause).to_string(),
+                ),
+            }
+        }
+        e => DataBaseErrorType::Io(e.to_string()),
+    }
+}
+
+/// a value in `column` that doesn't make sense, as `e`
+fn bad_value(column: usize, kind: Type, e: DataBaseErrorType) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(column, kind, Box::new(e))
+}
+
+fn to_millis(time: DateTime<Utc>) -> i64 {
+    time.timestamp_millis()
+}
+
+fn parse_uuid(uuid: &str) -> Result<Uuid, DataBaseErrorType> {
+    Uuid::parse_str(uuid)
+        .map_err(|e| DataBaseErrorType::Serialization(format!("paste uuid {:?}: {}", uuid, e)))
+}
+
+fn from_row(row: &Row) -> rusqlite::Result<DataBaseItem> {
+    let uuid: String = row.get(0)?;
+    let uuid = parse_uuid(&uuid).map_err(|e| bad_value(0, Type::Text, e))?;
+    // times out of range make the paste corrupt
+    let time = |column: usize| -> rusqlite::Result<Option<DateTime<Utc>>> {
+        match row.get::<_, Option<i64>>(column)? {
+            Some(millis) => match Utc.timestamp_millis_opt(millis).single() {
+                Some(time) => Ok(Some(time)),
+                None => Err(bad_value(
+                    column,
+                    Type::Integer,
+                    DataBaseErrorType::Corrupt(uuid),
+                )),
+            },
+            None => Ok(None),
+        }
+    };
+    let required = |column: usize| -> rusqlite::Result<DateTime<Utc>> {
+        time(column)?.ok_or_else(|| bad_value(column, Type::Null, DataBaseErrorType::Corrupt(uuid)))
+    };
+    Ok(DataBaseItem {
+        destroy_time: time(4)?,
+        max_views: row.get::<_, Option<i64>>(11)?.map(|v| v as u64),
+        custom_url: row.get(2)?,
+        uuid,
+        hash: row.get(3)?,
+        short: row.get(1)?,
+        secret_hash: row.get(5)?,
+        owner: row.get(6)?,
+        created_at: required(7)?,
+        updated_at: required(8)?,
+        stats: ViewStats {
+            views: row.get::<_, i64>(10)? as u64,
+            last_viewed_at: time(9)?,
+        },
+        data: data_from_row(row, 12, || DataBaseErrorType::Corrupt(uuid))?,
+    })
+}
+
+/// the content in the kind, content, blob and size columns starting at `first`,
+/// reported as `corrupt` when it can't be read
+fn data_from_row(
+    row: &Row,
+    first: usize,
+    corrupt: impl Fn() -> DataBaseErrorType,
+) -> rusqlite::Result<DataType> {
+    let kind: String = row.get(first)?;
+    let content: Option<Vec<u8>> = row.get(first + 1)?;
+    let blob: Option<String> = row.get(first + 2)?;
+    let size: i64 = row.get(first + 3)?;
+    let utf8 = |content: Vec<u8>| {
+        String::from_utf8(content).map_err(|_| bad_value(first + 1, Type::Blob, corrupt()))
+    };
+    Ok(match (kind.as_str(), blob, content) {
+        ("text" | "binary", Some(digest), _) => DataType::Blob {
+            digest,
+            size: size as u64,
+            text: kind == "text",
+        },
+        ("text", None, content) => DataType::Text(utf8(content.unwrap_or_default())?),
+        ("link", None, content) => DataType::ShortLink(utf8(content.unwrap_or_default())?),
+        ("binary", None, content) => DataType::Binary(content.unwrap_or_default()),
+        _ => return Err(bad_value(first, Type::Text, corrupt())),
+    })
+}
+
+/// kind, inline content, blob digest and size columns of `data`
+fn content_columns(data: &DataType) -> (&str, Option<&[u8]>, Option<&str>, i64) {
+    match data {
+        DataType::Text(t) => ("text", Some(t.as_bytes()), None, t.len() as i64),
+        DataType::ShortLink(l) => ("link", Some(l.as_bytes()), None, l.len() as i64),
+        DataType::Binary(b) => ("binary", Some(b), None, b.len() as i64),
+        DataType::Blob { digest, size, text } => (
+            if *text { "text" } else { "binary" },
+            None,
+            Some(digest),
+            *size as i64,
+        ),
+    }
+}
+
+fn select_by(conn: &Connection, column: &str, key: &str) -> rusqlite::Result<Option<DataBaseItem>> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM pastes p JOIN contents c ON c.key = p.content_key WHERE p.{} = ?1",
+            COLUMNS, column
+        ),
+        params![key],
+        from_row,
+    )
+    .optional()
+}
+
+/// take a reference to the content under `key`, storing `data` there if no
+/// other paste has this content yet
+fn acquire_content(conn: &Connection, key: &str, data: &DataType) -> rusqlite::Result<()> {
+    let (kind, content, blob, size) = content_columns(data);
+    conn.execute(
+        "INSERT INTO contents (key, refs, kind, content, blob, size)
+         VALUES (?1, 1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (key) DO UPDATE SET refs = refs + 1",
+        params![key, kind, content, blob, size],
+    )?;
+    Ok(())
+}
+
+/// drop a reference to the content under `key`, returning the content when
+/// this was the last one
+fn release_content(conn: &Connection, key: &str) -> rusqlite::Result<Option<DataType>> {
+    conn.execute(
+        "UPDATE contents SET refs = refs - 1 WHERE key = ?1",
+        params![key],
+    )?;
+    let released = conn
+        .query_row(
+            "SELECT kind, content, blob, size FROM contents WHERE key = ?1 AND refs <= 0",
+            params![key],
+            |row| {
+                data_from_row(row, 0, || {
+                    DataBaseErrorType::Serialization(format!("content {} can't be read", key))
+                })
+            },
+        )
+        .optional()?;
+    if released.is_some() {
+        conn.execute("DELETE FROM contents WHERE key = ?1", params![key])?;
+    }
+    Ok(released)
+}
+
+/// insert `data` along with its view statistics, taking a reference to its
+/// content
+fn insert_paste(conn: &Connection, data: &DataBaseItem) -> rusqlite::Result<()> {
+    let record = data.record();
+    acquire_content(conn, &record.content, &data.data)?;
+    conn.execute(
+        "INSERT INTO pastes (uuid, short, custom_url, hash, destroy_time, secret_hash, \
+         owner, created_at, updated_at, last_viewed_at, views, max_views, content_key) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            record.uuid.to_string(),
+            record.short,
+            record.custom_url,
+            record.hash,
+            record.destroy_time.map(to_millis),
+            record.secret_hash,
+            record.owner,
+            to_millis(record.created_at),
+            to_millis(record.updated_at),
+            data.stats.last_viewed_at.map(to_millis),
+            data.stats.views as i64,
+            record.max_views.map(|v| v as i64),
+            record.content,
+        ],
+    )?;
+    Ok(())
+}
+
+fn lookup(conn: &Connection, key: &str) -> rusqlite::Result<Option<DataBaseItem>> {
+    // short -> custom
+    if let Some(item) = select_by(conn, "short", key)? {
+        return Ok(Some(item));
+    }
+    select_by(conn, "custom_url", key)
+}
+
+impl Store for SqliteStore {
+    fn blobs(&self) -> &BlobStore {
+        &self.blobs
+    }
+
+    fn add_record(&self, data: &mut DataBaseItem) -> Result<(), DataBaseErrorType> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(failed)?;
+        if let Some(existing) = select_by(&tx, "uuid", &data.uuid.to_string()).map_err(failed)? {
+            return Err(DataBaseErrorType::Existed(Box::new(existing)));
+        }
+        if let Some(url) = &data.custom_url {
+            if let Some(existing) = lookup(&tx, url).map_err(failed)? {
+                return Err(DataBaseErrorType::Existed(Box::new(existing)));
+            }
+        }
+        let mut short = None;
+        for candidate in data.short_candidates() {
+            // short ids and custom urls are looked up by the same keys
+            match lookup(&tx, &candidate).map_err(failed)? {
+                None => {
+                    short = Some(candidate);
+                    break;
+                }
+                Some(existing) if data.is_duplicate_of(&existing) => {
+                    return Err(DataBaseErrorType::Existed(Box::new(existing)));
+                }
+                Some(_) => {}
+            }
+        }
+        data.short = short.ok_or(DataBaseErrorType::Failed)?;
+        insert_paste(&tx, data).map_err(failed)?;
+        tx.commit().map_err(failed)
+    }
+
+    fn restore_record(&self, data: &DataBaseItem) -> Result<(), DataBaseErrorType> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(failed)?;
+        let mut existing = select_by(&tx, "uuid", &data.uuid.to_string()).map_err(failed)?;
+        if existing.is_none() {
+            existing = lookup(&tx, &data.short).map_err(failed)?;
+        }
+        if let (None, Some(url)) = (&existing, &data.custom_url) {
+            existing = lookup(&tx, url).map_err(failed)?;
+        }
+        if let Some(existing) = existing {
+            return Err(DataBaseErrorType::Existed(Box::new(existing)));
+        }
+        insert_paste(&tx, data).map_err(failed)?;
+        tx.commit().map_err(failed)
+    }
+
+    fn query_record(&self, key: &str) -> Result<DataBaseItem, DataBaseErrorType> {
+        lookup(&self.conn(), key)
+            .map_err(failed)?
+            .ok_or(DataBaseErrorType::NotFound)
+    }
+
+    fn get_record(&self, key: Uuid) -> Result<DataBaseItem, DataBaseErrorType> {
+        select_by(&self.conn(), "uuid", &key.to_string())
+            .map_err(failed)?
+            .ok_or(DataBaseErrorType::NotFound)
+    }
+
+    fn replace_data(
+        &self,
+        key: Uuid,
+        value: DataType,
+        hash: blake3::Hash,
+    ) -> Result<Option<DataType>, DataBaseErrorType> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(failed)?;
+        let mut item = select_by(&tx, "uuid", &key.to_string())
+            .map_err(failed)?
+            .ok_or(DataBaseErrorType::NotFound)?;
+        let old_content = item.content_key();
+        item.data = value;
+        item.hash = String::from(hash.to_hex().as_str());
+        item.updated_at = Utc::now();
+        let record = item.record();
+        if record.content != old_content {
+            acquire_content(&tx, &record.content, &item.data).map_err(failed)?;
+        }
+        tx.execute(
+            "UPDATE pastes SET hash = ?2, content_key = ?3, updated_at = ?4 WHERE uuid = ?1",
+            params![
+                key.to_string(),
+                record.hash,
+                record.content,
+                to_millis(record.updated_at)
+            ],
+        )
+        .map_err(failed)?;
+        let mut released = None;
+        if record.content != old_content {
+            released = release_content(&tx, &old_content).map_err(failed)?;
+        }
+        tx.commit().map_err(failed)?;
+        Ok(released)
+    }
+
+    fn set_expiry(
+        &self,
+        key: Uuid,
+        destroy_time: Option<DateTime<Utc>>,
+    ) -> Result<(), DataBaseErrorType> {
+        let updated = self
+            .conn()
+            .execute(
+                "UPDATE pastes SET destroy_time = ?2 WHERE uuid = ?1",
+                params![key.to_string(), destroy_time.map(to_millis)],
+            )
+            .map_err(failed)?;
+        if updated == 0 {
+            return Err(DataBaseErrorType::NotFound);
+        }
+        Ok(())
+    }
+
+    fn remove_record(&self, key: Uuid) -> Result<Option<DataType>, DataBaseErrorType> {
+        let mut conn = self.conn();
+        let tx = conn.transaction().map_err(failed)?;
+        let data = select_by(&tx, "uuid", &key.to_string())
+            .map_err(failed)?
+            .ok_or(DataBaseErrorType::NotFound)?;
+        tx.execute(
+            "DELETE FROM pastes WHERE uuid = ?1",
+            params![key.to_string()],
+        )
+        .map_err(failed)?;
+        let released = release_content(&tx, &data.content_key()).map_err(failed)?;
+        tx.commit().map_err(failed)?;
+        Ok(released)
+    }
+
+    fn expired_records(&self, now: DateTime<Utc>) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT uuid FROM pastes WHERE destroy_time < ?1 ORDER BY destroy_time")
+            .map_err(failed)?;
+        let rows = stmt
+            .query_map(params![to_millis(now)], |row| row.get::<_, String>(0))
+            .map_err(failed)?;
+        rows.map(|uuid| parse_uuid(&uuid.map_err(failed)?))
+            .collect()
+    }
+
+    fn record_view(&self, key: Uuid, at: DateTime<Utc>) -> Result<u64, DataBaseErrorType> {
+        let views: Option<i64> = self
+            .conn()
+            .query_row(
+                "UPDATE pastes SET views = views + 1, last_viewed_at = ?2 WHERE uuid = ?1 \
+                 RETURNING views",
+                params![key.to_string(), to_millis(at)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(failed)?;
+        views.map(|v| v as u64).ok_or(DataBaseErrorType::NotFound)
+    }
+
+    fn list_records(
+        &self,
+        owner: &str,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<DataBaseItem>, DataBaseErrorType> {
+        let (created_at, uuid) = match after {
+            Some((created_at, uuid)) => (to_millis(created_at), uuid.to_string()),
+            None => (i64::MAX, String::new()),
+        };
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT {} FROM pastes p JOIN contents c ON c.key = p.content_key \
+                 WHERE p.owner = ?1 AND (p.created_at < ?2 OR (p.created_at = ?2 AND p.uuid < ?3)) \
+                 ORDER BY p.created_at DESC, p.uuid DESC LIMIT ?4",
+                COLUMNS
+            ))
+            .map_err(failed)?;
+        let rows = stmt
+            .query_map(params![owner, created_at, uuid, limit as i64], from_row)
+            .map_err(failed)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(failed)
+    }
+
+    fn add_user(&self, user: &User) -> Result<bool, DataBaseErrorType> {
+        let inserted = self
+            .conn()
+            .execute(
+                "INSERT INTO users (name, key_hash, admin) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (name) DO NOTHING",
+                params![user.name, user.key_hash, user.admin],
+            )
+            .map_err(failed)?;
+        Ok(inserted > 0)
+    }
+
+    fn find_user(&self, key_hash: &str) -> Result<User, DataBaseErrorType> {
+        self.conn()
+            .query_row(
+                "SELECT name, key_hash, admin FROM users WHERE key_hash = ?1",
+                params![key_hash],
+                user_from_row,
+            )
+            .optional()
+            .map_err(failed)?
+            .ok_or(DataBaseErrorType::NotFound)
+    }
+
+    fn uuids(&self) -> Result<Vec<Uuid>, DataBaseErrorType> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT uuid FROM pastes").map_err(failed)?;
+        let rows = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(failed)?;
+        rows.map(|uuid| parse_uuid(&uuid.map_err(failed)?))
+            .collect()
+    }
+
+    fn users(&self) -> Result<Vec<User>, DataBaseErrorType> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT name, key_hash, admin FROM users")
+            .map_err(failed)?;
+        let rows = stmt.query_map([], user_from_row).map_err(failed)?;
+        rows.collect::<rusqlite::Result<_>>().map_err(failed)
+    }
+}
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        name: row.get(0)?,
+        key_hash: row.get(1)?,
+        admin: row.get(2)?,
+    })
+}
@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{info, warn};
+
+use crate::model::{DataBaseErrorType, Db};
+
+/// delete every expired paste, returning how many were
+pub fn sweep(db: Db) -> Result<usize, DataBaseErrorType> {
+    let expired = db.expired_records(Utc::now())?;
+    let mut purged = 0;
+    for uuid in expired {
+        match db.delete_record(uuid) {
+            Ok(_) => purged += 1,
+            Err(_) => warn!("sweeper failed to delete {}", uuid),
+        }
+    }
+    Ok(purged)
+}
+
+pub async fn run(db: Db, interval: u64) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        ticker.tick().await;
+        let db = db.clone();
+        match tokio::task::spawn_blocking(move || sweep(db)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(purged)) => info!("sweeper purged {} expired pastes", purged),
+            Ok(Err(e)) => warn!("sweeper failed to look up expired pastes: {}", e),
+            Err(e) => warn!("sweeper task failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::{convert::TryFrom, fmt};
+
+use chrono::{prelude::*, Duration};
+use serde::{Deserialize, Serialize};
+
+const UNITS: &[(char, u64)] = &[
+    ('s', 1),
+    ('m', 60),
+    ('h', 3600),
+    ('d', 86400),
+    ('w', 604800),
+];
+
+/// how long a paste lives, written as seconds or like `90m`, `1h` or `7d`
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(try_from = "RawTtl", into = "String")]
+pub struct Ttl(u64);
+
+impl Ttl {
+    pub fn parse(s: &str) -> Result<Ttl, String> {
+        let s = s.trim();
+        let (number, unit) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_lowercase()),
+            _ => (s, 's'),
+        };
+        let scale = UNITS
+            .iter()
+            .find(|(u, _)| *u == unit)
+            .map(|(_, scale)| *scale)
+            .ok_or_else(|| format!("unknown unit in duration {:?}", s))?;
+        let number = number
+            .parse::<u64>()
+            .map_err(|_| format!("invalid duration {:?}", s))?;
+        match number.checked_mul(scale) {
+            Some(0) => Err(String::from("duration must be positive")),
+            Some(seconds) => Ok(Ttl(seconds)),
+            None => Err(format!("duration {:?} is too long", s)),
+        }
+    }
+
+    /// `now` plus this, unless that is beyond what can be represented
+    pub fn after(self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let duration = Duration::from_std(std::time::Duration::from_secs(self.0)).ok()?;
+        now.checked_add_signed(duration)
+    }
+}
+
+impl fmt::Display for Ttl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match UNITS
+            .iter()
+            .rev()
+            .find(|(_, scale)| self.0.is_multiple_of(*scale))
+        {
+            Some((unit, scale)) => write!(f, "{}{}", self.0 / scale, unit),
+            None => write!(f, "{}s", self.0),
+        }
+    }
+}
+
+impl From<Ttl> for String {
+    fn from(ttl: Ttl) -> Self {
+        ttl.to_string()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTtl {
+    Seconds(u64),
+    Text(String),
+}
+
+impl TryFrom<RawTtl> for Ttl {
+    type Error = String;
+
+    fn try_from(raw: RawTtl) -> Result<Self, Self::Error> {
+        match raw {
+            RawTtl::Seconds(seconds) => Ttl::parse(&seconds.to_string()),
+            RawTtl::Text(text) => Ttl::parse(&text),
+        }
+    }
+}
+
+/// how long pastes may live
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Retention {
+    /// for pastes uploaded without `sunset`
+    pub default: Option<Ttl>,
+    pub min: Option<Ttl>,
+    pub max: Option<Ttl>,
+}
+
+impl Retention {
+    /// the default has to lie between the minimum and the maximum
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(format!("min_ttl {} is longer than max_ttl {}", min, max));
+            }
+        }
+        let default = match self.default {
+            Some(default) => default,
+            None => return Ok(()),
+        };
+        if let Some(min) = self.min.filter(|min| default < *min) {
+            return Err(format!(
+                "default_ttl {} is shorter than min_ttl {}",
+                default, min
+            ));
+        }
+        if let Some(max) = self.max.filter(|max| default > *max) {
+            return Err(format!(
+                "default_ttl {} is longer than max_ttl {}",
+                default, max
+            ));
+        }
+        Ok(())
+    }
+
+    /// when a paste asked to live for `sunset` expires, never when `None`
+    pub fn expiry(
+        &self,
+        sunset: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<DateTime<Utc>>, String> {
+        let ttl = match sunset {
+            Some(sunset) => Ttl::parse(sunset).map_err(|e| format!("sunset: {}", e))?,
+            // pastes can't live forever when there is a maximum
+            None => match self.default.or(self.max) {
+                Some(ttl) => ttl,
+                None => return Ok(None),
+            },
+        };
+        if let Some(min) = self.min.filter(|min| ttl < *min) {
+            return Err(format!("sunset must be at least {}", min));
+        }
+        if let Some(max) = self.max.filter(|max| ttl > *max) {
+            return Err(format!("sunset must be at most {}", max));
+        }
+        ttl.after(now)
+            .map(Some)
+            .ok_or_else(|| String::from("sunset is too far in the future"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seconds_and_units() {
+        assert_eq!(Ttl::parse("90"), Ok(Ttl(90)));
+        assert_eq!(Ttl::parse(" 90m "), Ok(Ttl(5400)));
+        assert_eq!(Ttl::parse("1H"), Ok(Ttl(3600)));
+        assert_eq!(Ttl::parse("7d"), Ok(Ttl(604800)));
+        assert_eq!(Ttl::parse("2w").unwrap().to_string(), "2w");
+    }
+
+    #[test]
+    fn refuses_what_is_no_duration() {
+        for s in [
+            "",
+            "0",
+            "0d",
+            "-1",
+            "1.5h",
+            "3y",
+            "d",
+            "18446744073709551615w",
+        ] {
+            assert!(Ttl::parse(s).is_err(), "{:?} was parsed", s);
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::HashMap, fmt, path::PathBuf};
+
+use bytes::Buf;
+use futures::{Stream, TryStreamExt};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+use warp::{http::HeaderMap, multipart::FormData, Rejection};
+
+use crate::controller::ResponseFormat;
+use crate::error::Error;
+
+/// options a raw body upload can pass as `?name=value` or an `X-Name` header
+const UPLOAD_OPTIONS: &[&str] = &["sunset", "burn", "max-views"];
+
+/// form fields holding the paste content, which get spooled to disk
+const CONTENT_FIELDS: &[&str] = &["c", "content"];
+
+/// where uploaded content is written while it is being received
+#[derive(Debug, Clone)]
+pub struct Spool {
+    pub dir: PathBuf,
+    pub max_length: u64,
+}
+
+/// content received from a client, hashed as it was written to a temporary
+/// file which is removed once this is dropped
+#[derive(Debug)]
+pub struct SpooledFile {
+    pub path: PathBuf,
+    pub hash: blake3::Hash,
+    pub size: u64,
+    /// whether the whole content is valid utf-8
+    pub utf8: bool,
+}
+
+impl SpooledFile {
+    pub async fn read(&self) -> std::io::Result<Vec<u8>> {
+        tokio::fs::read(&self.path).await
+    }
+}
+
+/// utf-8 validation of content arriving in chunks, which may split characters
+#[derive(Default)]
+struct Utf8Check {
+    invalid: bool,
+    pending: Vec<u8>,
+}
+
+impl Utf8Check {
+    fn update(&mut self, chunk: &[u8]) {
+        if self.invalid {
+            return;
+        }
+        self.pending.extend_from_slice(chunk);
+        match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.clear(),
+            // an incomplete character at the end, keep it for the next chunk
+            Err(e) if e.error_len().is_none() => {
+                self.pending = self.pending.split_off(e.valid_up_to());
+            }
+            Err(_) => {
+                self.invalid = true;
+                self.pending = Vec::new();
+            }
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        !self.invalid && self.pending.is_empty()
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Debug)]
+pub enum SpoolError {
+    Io(std::io::Error),
+    Stream(warp::Error),
+    TooLarge,
+}
+
+impl From<std::io::Error> for SpoolError {
+    fn from(e: std::io::Error) -> Self {
+        SpoolError::Io(e)
+    }
+}
+
+impl fmt::Display for SpoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpoolError::Io(e) => write!(f, "failed to store upload: {}", e),
+            SpoolError::Stream(e) => write!(f, "failed to receive upload: {}", e),
+            SpoolError::TooLarge => write!(f, "upload is too large"),
+        }
+    }
+}
+
+impl From<SpoolError> for Error {
+    fn from(e: SpoolError) -> Self {
+        match e {
+            SpoolError::Io(_) => Error::Internal(e.to_string()),
+            SpoolError::Stream(_) => Error::BadRequest(e.to_string()),
+            SpoolError::TooLarge => Error::TooLarge(e.to_string()),
+        }
+    }
+}
+
+/// the paste content plus every other field of an upload, read either from a
+/// multipart form or a raw body
+#[derive(Debug, Default)]
+pub struct UploadForm {
+    pub content: Option<SpooledFile>,
+    pub fields: HashMap<String, Vec<u8>>,
+}
+
+impl UploadForm {
+    pub fn get(&self, name: &str) -> Option<&Vec<u8>> {
+        self.fields.get(name)
+    }
+}
+
+impl Spool {
+    pub async fn write<S, B>(&self, mut stream: S) -> Result<SpooledFile, SpoolError>
+    where
+        S: Stream<Item = Result<B, warp::Error>> + Unpin,
+        B: Buf,
+    {
+        let path = self.dir.join(Uuid::new_v4().to_string());
+        let mut file = tokio::fs::File::create(&path).await?;
+        // from here on the file is cleaned up by the drop of `spooled`
+        let mut spooled = SpooledFile {
+            path,
+            hash: blake3::Hash::from([0; 32]),
+            size: 0,
+            utf8: false,
+        };
+        let mut hasher = blake3::Hasher::new();
+        let mut utf8 = Utf8Check::default();
+        while let Some(mut buf) = stream.try_next().await.map_err(SpoolError::Stream)? {
+            while buf.has_remaining() {
+                let chunk = buf.chunk();
+                spooled.size += chunk.len() as u64;
+                if spooled.size > self.max_length {
+                    return Err(SpoolError::TooLarge);
+                }
+                hasher.update(chunk);
+                utf8.update(chunk);
+                file.write_all(chunk).await?;
+                let len = chunk.len();
+                buf.advance(len);
+            }
+        }
+        file.flush().await?;
+        spooled.hash = hasher.finalize();
+        spooled.utf8 = utf8.is_valid();
+        Ok(spooled)
+    }
+}
+
+/// the content is limited by the spool, the other fields together may not
+/// be larger than that either
+pub async fn read_multipart_form(
+    mut form: FormData,
+    spool: Spool,
+    format: ResponseFormat,
+) -> Result<UploadForm, Rejection> {
+    let mut res = UploadForm::default();
+    let mut fields_size = 0;
+    while let Some(mut part) = form
+        .try_next()
+        .await
+        .map_err(|e| format.reject(Error::BadRequest(format!("invalid form: {}", e))))?
+    {
+        let name = String::from(part.name());
+        if CONTENT_FIELDS.contains(&name.as_str()) {
+            let content = spool
+                .write(Box::pin(part.stream()))
+                .await
+                .map_err(|e| format.reject(Error::from(e)))?;
+            res.content = Some(content);
+        } else {
+            let mut value = Vec::new();
+            while let Some(data) = part.data().await {
+                let data = data.map_err(|e| format.reject(SpoolError::Stream(e).into()))?;
+                fields_size += data.remaining() as u64;
+                if fields_size > spool.max_length {
+                    return Err(format.reject(SpoolError::TooLarge.into()));
+                }
+                value.extend_from_slice(data.chunk());
+            }
+            res.fields.insert(name, value);
+        }
+    }
+    Ok(res)
+}
+
+pub async fn read_raw_body<S, B>(
+    body: S,
+    headers: HeaderMap,
+    query: HashMap<String, String>,
+    spool: Spool,
+    format: ResponseFormat,
+) -> Result<UploadForm, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send,
+    B: Buf,
+{
+    let mut res = UploadForm::default();
+    for &option in UPLOAD_OPTIONS {
+        let value = query
+            .get(option)
+            .map(|v| v.as_bytes().to_vec())
+            .or_else(|| {
+                headers
+                    .get(format!("x-{}", option))
+                    .map(|v| v.as_bytes().to_vec())
+            });
+        if let Some(value) = value {
+            res.fields.insert(String::from(option), value);
+        }
+    }
+    let content = spool
+        .write(Box::pin(body))
+        .await
+        .map_err(|e| format.reject(Error::from(e)))?;
+    res.content = Some(content);
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(chunks: &[&[u8]]) -> bool {
+        let mut utf8 = Utf8Check::default();
+        for chunk in chunks {
+            utf8.update(chunk);
+        }
+        utf8.is_valid()
+    }
+
+    #[test]
+    fn utf8_characters_may_be_split_over_chunks() {
+        let text = "größer als €".as_bytes();
+        for i in 0..=text.len() {
+            for j in i..=text.len() {
+                assert!(check(&[&text[..i], &text[i..j], &text[j..]]));
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_or_unfinished_utf8_is_caught() {
+        let euro = "€".as_bytes();
+        assert!(!check(&[b"ab", &euro[..2]]));
+        assert!(!check(&[&euro[..1], b"c", &euro[1..]]));
+        assert!(!check(&[b"\xff", "valid".as_bytes()]));
+    }
+}